@@ -1,22 +1,32 @@
 use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::io::BufRead;
+use std::io::{stdout, BufRead, BufWriter, Write};
 
 use anyhow::{ensure, Context, Result};
 use clap::{App, Arg, ArgMatches};
-use finalfusion::similarity::Analogy;
+use finalfusion::prelude::*;
+use rayon::ThreadPoolBuilder;
 use stdinout::Input;
 
 use crate::io::{read_embeddings_view, EmbeddingFormat};
+use crate::neighbors::Candidates;
+use crate::objective::AnalogyObjective;
+use crate::report::{QueryFormat, QueryWriter};
 use crate::similarity::SimilarityMeasure;
 use crate::FinalfusionApp;
 
 pub struct AnalogyApp {
+    batch_size: usize,
+    candidates_filename: Option<String>,
     embeddings_filename: String,
     embedding_format: EmbeddingFormat,
     input_filename: Option<String>,
     excludes: [bool; 3],
     k: usize,
+    n_threads: usize,
+    objective: AnalogyObjective,
+    output_format: QueryFormat,
+    restrict_vocab: Option<usize>,
     similarity: SimilarityMeasure,
 }
 
@@ -24,12 +34,28 @@ impl FinalfusionApp for AnalogyApp {
     fn app() -> App<'static, 'static> {
         App::new("analogy")
             .about("Find words that fit an analogy")
+            .arg(
+                Arg::with_name("batch_size")
+                    .long("batch-size")
+                    .value_name("N")
+                    .help("Number of queries that are processed at once")
+                    .takes_value(true)
+                    .default_value("1000"),
+            )
+            .arg(
+                Arg::with_name("candidates")
+                    .long("candidates")
+                    .value_name("FILE")
+                    .help("Only return words from FILE (one word per line)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("format")
                     .short("f")
                     .value_name("FORMAT")
                     .takes_value(true)
                     .possible_values(&[
+                        "auto",
                         "fasttext",
                         "finalfusion",
                         "finalfusion_mmap",
@@ -37,7 +63,7 @@ impl FinalfusionApp for AnalogyApp {
                         "text",
                         "textdims",
                     ])
-                    .default_value("finalfusion"),
+                    .default_value("auto"),
             )
             .arg(
                 Arg::with_name("neighbors")
@@ -47,7 +73,23 @@ impl FinalfusionApp for AnalogyApp {
                     .takes_value(true)
                     .default_value("10"),
             )
+            .arg(AnalogyObjective::new_clap_arg())
+            .arg(QueryFormat::new_clap_arg())
+            .arg(
+                Arg::with_name("restrict_vocab")
+                    .long("restrict-vocab")
+                    .value_name("N")
+                    .help("Only return the N most frequent words")
+                    .takes_value(true),
+            )
             .arg(SimilarityMeasure::new_clap_arg())
+            .arg(
+                Arg::with_name("threads")
+                    .long("threads")
+                    .value_name("N")
+                    .help("Number of threads (default: logical_cpus / 2)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("EMBEDDINGS")
                     .help("Embeddings file")
@@ -82,6 +124,17 @@ impl FinalfusionApp for AnalogyApp {
             .map(|v| v.parse().context("Cannot parse k"))
             .transpose()?
             .unwrap();
+        let batch_size = matches
+            .value_of("batch_size")
+            .map(|v| v.parse().context("Cannot parse batch size"))
+            .transpose()?
+            .unwrap();
+        ensure!(batch_size > 0, "The batch size must be at least 1");
+        let n_threads = matches
+            .value_of("threads")
+            .map(|v| v.parse().context("Cannot parse number of threads"))
+            .transpose()?
+            .unwrap_or(num_cpus::get() / 2);
         let excludes = matches
             .values_of("include")
             .map(|v| {
@@ -93,66 +146,134 @@ impl FinalfusionApp for AnalogyApp {
             })
             .unwrap_or_else(|| [true, true, true]);
 
+        let objective = AnalogyObjective::parse_clap_matches(matches)?;
+        let candidates_filename = matches.value_of("candidates").map(ToOwned::to_owned);
+        let restrict_vocab = matches
+            .value_of("restrict_vocab")
+            .map(|v| v.parse().context("Cannot parse vocabulary restriction"))
+            .transpose()?;
+
+        let output_format = QueryFormat::parse_clap_matches(matches)?;
         let similarity = SimilarityMeasure::parse_clap_matches(matches)?;
+        ensure!(
+            similarity.is_cosine_based(),
+            "The {} similarity measure is not supported for analogies",
+            similarity
+        );
 
         Ok(AnalogyApp {
+            batch_size,
+            candidates_filename,
             embeddings_filename,
             embedding_format,
             input_filename,
             excludes,
             k,
+            n_threads,
+            objective,
+            output_format,
+            restrict_vocab,
             similarity,
         })
     }
 
     fn run(&self) -> Result<()> {
+        ThreadPoolBuilder::new()
+            .num_threads(self.n_threads)
+            .build_global()
+            .unwrap();
+
         let embeddings = read_embeddings_view(&self.embeddings_filename, self.embedding_format)
             .context("Cannot read embeddings")?;
+        let candidates = Candidates::from_options(
+            embeddings.vocab(),
+            self.restrict_vocab,
+            self.candidates_filename.as_deref(),
+        )?;
         let input = Input::from(self.input_filename.as_ref());
         let reader = input.buf_read().context("Cannot open input for reading")?;
 
+        let stdout = stdout();
+        let mut writer = QueryWriter::new(self.output_format, BufWriter::new(stdout.lock()))?;
+
+        let mut batch = Vec::with_capacity(self.batch_size);
         for line in reader.lines() {
             let line = line.context("Cannot read line")?.trim().to_owned();
             if line.is_empty() {
                 continue;
             }
 
-            let split_line: Vec<&str> = line.split_whitespace().collect();
+            let split_line: Vec<String> = line.split_whitespace().map(ToOwned::to_owned).collect();
             ensure!(
                 split_line.len() == 3,
                 "Query does not consist of three tokens: {}",
                 line
             );
 
-            let results = match embeddings.analogy_masked(
-                [split_line[0], split_line[1], split_line[2]],
-                self.excludes,
-                self.k,
-            ) {
+            batch.push(split_line);
+            if batch.len() == self.batch_size {
+                self.process_batch(&embeddings, &candidates, &batch, &mut writer)?;
+                batch.clear();
+            }
+        }
+
+        self.process_batch(&embeddings, &candidates, &batch, &mut writer)?;
+
+        writer.flush()
+    }
+}
+
+impl AnalogyApp {
+    /// Answer a batch of queries, each consisting of three tokens.
+    fn process_batch(
+        &self,
+        embeddings: &Embeddings<VocabWrap, StorageViewWrap>,
+        candidates: &Candidates,
+        batch: &[Vec<String>],
+        writer: &mut QueryWriter<impl Write>,
+    ) -> Result<()> {
+        let queries = batch
+            .iter()
+            .map(|query| [query[0].as_str(), query[1].as_str(), query[2].as_str()])
+            .collect::<Vec<_>>();
+
+        let results =
+            self.objective
+                .analogies(embeddings, candidates, &queries, self.excludes, self.k);
+
+        for (query, results) in queries.iter().zip(results) {
+            let query_text = query.join(" ");
+            let results = match results {
                 Ok(results) => results,
                 Err(success) => {
-                    print_missing_tokens(&split_line, &success);
+                    writer.write_missing(&query_text, &missing_tokens(query, &success))?;
                     continue;
                 }
             };
 
-            for analogy in results {
-                println!("{}\t{}", analogy.word(), self.similarity.as_f32(&analogy));
-            }
+            writer.write_neighbors(
+                &query_text,
+                results.into_iter().map(|analogy| {
+                    let score = if self.objective.is_cosine() {
+                        self.similarity.convert_cosine(analogy.score)
+                    } else {
+                        analogy.score
+                    };
+                    (analogy.word, score)
+                }),
+            )?;
         }
 
         Ok(())
     }
 }
 
-fn print_missing_tokens(tokens: &[&str], successful: &[bool]) {
+fn missing_tokens<'a>(tokens: &[&'a str], successful: &[bool]) -> Vec<&'a str> {
     assert_eq!(tokens.len(), successful.len());
 
-    let missing = tokens
+    tokens
         .iter()
         .zip(successful)
         .filter_map(|(&token, &success)| if !success { Some(token) } else { None })
-        .collect::<Vec<_>>();
-
-    eprintln!("Could not compute embedding(s) for: {}", missing.join(", "));
+        .collect()
 }
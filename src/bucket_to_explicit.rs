@@ -1,6 +1,6 @@
 use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 
 use anyhow::{Context, Result};
 use clap::{App, Arg, ArgMatches};
@@ -8,7 +8,7 @@ use finalfusion::compat::fasttext::ReadFastText;
 use finalfusion::io::WriteEmbeddings;
 use finalfusion::prelude::*;
 
-use crate::io::EmbeddingFormat;
+use crate::io::{check_output, EmbeddingFormat, EmbeddingsWriter, Overwrite, PositionWriter};
 use crate::FinalfusionApp;
 
 // Argument constants
@@ -20,6 +20,7 @@ pub struct BucketToExplicitApp {
     input_filename: String,
     output_filename: String,
     format: EmbeddingFormat,
+    overwrite: Overwrite,
 }
 
 impl FinalfusionApp for BucketToExplicitApp {
@@ -48,6 +49,7 @@ impl FinalfusionApp for BucketToExplicitApp {
                     .takes_value(true)
                     .value_name("FORMAT"),
             )
+            .arg(Overwrite::new_clap_arg())
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
@@ -65,17 +67,16 @@ impl FinalfusionApp for BucketToExplicitApp {
             input_filename,
             output_filename,
             format,
+            overwrite: Overwrite::parse_clap_matches(matches),
         })
     }
 
     fn run(&self) -> Result<()> {
+        check_output(&self.input_filename, &self.output_filename, self.overwrite)?;
+
         let f = File::open(&self.input_filename)
             .context(format!("Cannot open input file: {}", self.input_filename))?;
         let mut reader = BufReader::new(f);
-        let f = File::create(&self.output_filename).context(format!(
-            "Cannot create embeddings file for writing: {}",
-            self.output_filename
-        ))?;
         let embeddings = match self.format {
             EmbeddingFormat::FinalFusion => {
                 Embeddings::<VocabWrap, StorageWrap>::mmap_embeddings(&mut reader).context(
@@ -93,8 +94,9 @@ impl FinalfusionApp for BucketToExplicitApp {
         };
         let conv = embeddings.try_to_explicit()?;
 
-        let mut writer = BufWriter::new(f);
-        conv.write_embeddings(&mut writer)
-            .context("Cannot write embeddings")
+        let mut writer = EmbeddingsWriter::create(&self.output_filename, self.overwrite)?;
+        conv.write_embeddings(&mut PositionWriter::new(&mut writer))
+            .context("Cannot write embeddings")?;
+        writer.finish()
     }
 }
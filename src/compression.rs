@@ -0,0 +1,214 @@
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+const XZ_LEVEL: u32 = 6;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    None,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression from the magic at the start of the data.
+    pub fn from_magic(data: &[u8]) -> Self {
+        use self::Compression::*;
+
+        if data.starts_with(GZIP_MAGIC) {
+            Gzip
+        } else if data.starts_with(XZ_MAGIC) {
+            Xz
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Zstd
+        } else {
+            None
+        }
+    }
+
+    /// Guess the compression from the extension of a filename.
+    pub fn from_extension(filename: &str) -> Self {
+        use self::Compression::*;
+
+        match Path::new(filename).extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Gzip,
+            Some("xz") => Xz,
+            Some("zst") | Some("zstd") => Zstd,
+            _ => None,
+        }
+    }
+
+    /// Wrap a reader to decompress its data.
+    pub fn decompress<'a>(self, read: impl BufRead + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        use self::Compression::*;
+
+        Ok(match self {
+            Gzip => Box::new(MultiGzDecoder::new(read)),
+            None => Box::new(read),
+            Xz => Box::new(XzDecoder::new_multi_decoder(read)),
+            Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(read)?),
+        })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::Compression::*;
+        let s = match self {
+            Gzip => "gzip",
+            None => "none",
+            Xz => "xz",
+            Zstd => "zstd",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// Writer that optionally compresses its data.
+///
+/// The compressed stream is only complete after calling `finish`.
+pub enum CompressedWriter<W>
+where
+    W: Write,
+{
+    Gzip(GzEncoder<W>),
+    Plain(W),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<W>),
+}
+
+impl<W> CompressedWriter<W>
+where
+    W: Write,
+{
+    pub fn new(write: W, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::Gzip => {
+                CompressedWriter::Gzip(GzEncoder::new(write, flate2::Compression::default()))
+            }
+            Compression::None => CompressedWriter::Plain(write),
+            Compression::Xz => CompressedWriter::Xz(XzEncoder::new(write, XZ_LEVEL)),
+            Compression::Zstd => {
+                CompressedWriter::Zstd(zstd::stream::write::Encoder::new(write, ZSTD_LEVEL)?)
+            }
+        })
+    }
+
+    /// Finish the compressed stream and return the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        use self::CompressedWriter::*;
+
+        match self {
+            Gzip(encoder) => encoder.finish(),
+            Plain(write) => Ok(write),
+            Xz(encoder) => encoder.finish(),
+            Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W> Write for CompressedWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        use self::CompressedWriter::*;
+
+        match self {
+            Gzip(encoder) => encoder.write(buf),
+            Plain(write) => write.write(buf),
+            Xz(encoder) => encoder.write(buf),
+            Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        use self::CompressedWriter::*;
+
+        match self {
+            Gzip(encoder) => encoder.flush(),
+            Plain(write) => write.flush(),
+            Xz(encoder) => encoder.flush(),
+            Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::{CompressedWriter, Compression};
+
+    const DATA: &[u8] = b"foo 1 2 3\nbar 4 5 6\n";
+
+    fn compress(compression: Compression) -> Vec<u8> {
+        let mut writer = CompressedWriter::new(Vec::new(), compression).unwrap();
+        writer.write_all(DATA).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn from_magic_detects_compressed_data() {
+        for &compression in &[
+            Compression::Gzip,
+            Compression::None,
+            Compression::Xz,
+            Compression::Zstd,
+        ] {
+            let compressed = compress(compression);
+            assert_eq!(Compression::from_magic(&compressed), compression);
+
+            let mut decompressed = Vec::new();
+            compression
+                .decompress(&compressed[..])
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, DATA);
+        }
+    }
+
+    #[test]
+    fn from_magic_short_data() {
+        assert_eq!(Compression::from_magic(&[]), Compression::None);
+        assert_eq!(Compression::from_magic(&[0x1f]), Compression::None);
+        assert_eq!(
+            Compression::from_magic(&[0x28, 0xb5, 0x2f]),
+            Compression::None
+        );
+    }
+
+    #[test]
+    fn from_extension() {
+        assert_eq!(
+            Compression::from_extension("embeds.fifu.gz"),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_extension("embeds.txt.xz"),
+            Compression::Xz
+        );
+        assert_eq!(
+            Compression::from_extension("embeds.vec.zst"),
+            Compression::Zstd
+        );
+        assert_eq!(
+            Compression::from_extension("embeds.fifu"),
+            Compression::None
+        );
+    }
+}
@@ -1,18 +1,30 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::io::BufRead;
-use std::sync::{Arc, Mutex};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
 use clap::{App, AppSettings, Arg, ArgMatches};
 use finalfusion::prelude::*;
-use finalfusion::similarity::Analogy;
 use finalfusion::vocab::Vocab;
 use indicatif::{ProgressBar, ProgressStyle};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
+use serde::Serialize;
 use stdinout::Input;
+use toml::Value;
 
-use crate::io::{read_embeddings_view, EmbeddingFormat};
+use crate::io::{
+    check_output, read_embeddings_view, AtomicOutput, EmbeddingFormat, Overwrite, STDIO_FILENAME,
+};
+use crate::objective::AnalogyObjective;
+use crate::report::ReportFormat;
+use crate::stats::{bootstrap_accuracy, mcnemar_exact};
 use crate::FinalfusionApp;
 
 static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
@@ -21,14 +33,40 @@ static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
 ];
 
 // Option constants
-static EMBEDDINGS: &str = "EMBEDDINGS";
-static ANALOGIES: &str = "ANALOGIES";
+static ANALOGY_FORMAT: &str = "analogy_format";
+static BOOTSTRAP: &str = "bootstrap";
+static CASE_INSENSITIVE: &str = "case_insensitive";
+static CONFIDENCE: &str = "confidence";
+static EMBEDDING_FORMAT: &str = "embedding_format";
+static OUTPUT: &str = "output";
+static PREDICTIONS: &str = "predictions";
+static PREDICTIONS_K: &str = "predictions_k";
+static RESTRICT: &str = "restrict";
+static SEED: &str = "seed";
+static SKIP_MALFORMED: &str = "skip_malformed";
 static THREADS: &str = "threads";
 
+// Argument constants
+static EMBEDDINGS: &str = "EMBEDDINGS";
+
 pub struct ComputeAccuracyApp {
     analogies_filename: Option<String>,
-    embeddings_filename: String,
+    analogy_format: AnalogyFormat,
+    bootstrap: Option<usize>,
+    case_insensitive: bool,
+    confidence: f64,
+    embeddings_filenames: Vec<String>,
+    embedding_format: EmbeddingFormat,
     n_threads: usize,
+    objective: AnalogyObjective,
+    output_filename: Option<String>,
+    output_format: ReportFormat,
+    overwrite: Overwrite,
+    predictions_filename: Option<String>,
+    predictions_k: usize,
+    restrict: Option<usize>,
+    seed: u64,
+    skip_malformed: bool,
 }
 
 impl FinalfusionApp for ComputeAccuracyApp {
@@ -36,6 +74,105 @@ impl FinalfusionApp for ComputeAccuracyApp {
         App::new("compute-accuracy")
             .about("Compute prediction accuracy on a set of analogies")
             .settings(DEFAULT_CLAP_SETTINGS)
+            .arg(
+                Arg::with_name(ANALOGY_FORMAT)
+                    .long("analogy-format")
+                    .value_name("FORMAT")
+                    .help(
+                        "Analogy format: google (file with : sections) or \
+                         bats (directory with a file per category)",
+                    )
+                    .takes_value(true)
+                    .possible_values(&["bats", "google"])
+                    .default_value("google"),
+            )
+            .arg(
+                Arg::with_name(BOOTSTRAP)
+                    .long("bootstrap")
+                    .value_name("N")
+                    .help("Compute confidence intervals of accuracies from N bootstrap samples")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name(CASE_INSENSITIVE)
+                    .short("i")
+                    .long("case-insensitive")
+                    .help("Match words in the analogies with the vocabulary ignoring case"),
+            )
+            .arg(
+                Arg::with_name(CONFIDENCE)
+                    .long("confidence")
+                    .value_name("LEVEL")
+                    .help("Confidence level of bootstrap confidence intervals")
+                    .takes_value(true)
+                    .default_value("0.95"),
+            )
+            .arg(
+                Arg::with_name(EMBEDDING_FORMAT)
+                    .short("f")
+                    .value_name("FORMAT")
+                    .takes_value(true)
+                    .possible_values(&[
+                        "auto",
+                        "fasttext",
+                        "finalfusion",
+                        "finalfusion_mmap",
+                        "floret",
+                        "text",
+                        "textdims",
+                        "word2vec",
+                    ])
+                    .default_value("auto"),
+            )
+            .arg(AnalogyObjective::new_clap_arg())
+            .arg(
+                Arg::with_name(OUTPUT)
+                    .short("o")
+                    .long("output")
+                    .value_name("FILE")
+                    .help("Write results to a file (default: standard output)")
+                    .takes_value(true),
+            )
+            .arg(ReportFormat::new_clap_arg())
+            .arg(Overwrite::new_clap_arg())
+            .arg(
+                Arg::with_name(PREDICTIONS)
+                    .long("predictions")
+                    .value_name("FILE")
+                    .help("Write the predictions for every analogy to a file (JSON lines)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name(PREDICTIONS_K)
+                    .long("predictions-k")
+                    .value_name("K")
+                    .help("Number of predictions per analogy to write")
+                    .takes_value(true)
+                    .default_value("5"),
+            )
+            .arg(
+                Arg::with_name(RESTRICT)
+                    .long("restrict")
+                    .value_name("N")
+                    .help(
+                        "Only use the first N words of the vocabulary as answers \
+                         and skip questions with other words",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name(SEED)
+                    .long("seed")
+                    .value_name("SEED")
+                    .help("Seed of the random number generator used for bootstrapping")
+                    .takes_value(true)
+                    .default_value("42"),
+            )
+            .arg(
+                Arg::with_name(SKIP_MALFORMED)
+                    .long("skip-malformed")
+                    .help("Skip malformed lines in the analogy file rather than failing"),
+            )
             .arg(
                 Arg::with_name(THREADS)
                     .long("threads")
@@ -45,49 +182,282 @@ impl FinalfusionApp for ComputeAccuracyApp {
             )
             .arg(
                 Arg::with_name(EMBEDDINGS)
-                    .help("Embedding file")
+                    .help(
+                        "Embedding file(s), followed by the analogy file when more than \
+                         one file is given (default analogy file: standard input)",
+                    )
+                    .value_name("EMBEDDINGS... [ANALOGIES]")
                     .index(1)
+                    .multiple(true)
                     .required(true),
             )
-            .arg(Arg::with_name(ANALOGIES).help("Analogy file").index(2))
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
-        let embeddings_filename = matches.value_of(EMBEDDINGS).unwrap().to_owned();
-        let analogies_filename = matches.value_of(ANALOGIES).map(ToOwned::to_owned);
+        let mut embeddings_filenames = matches
+            .values_of(EMBEDDINGS)
+            .unwrap()
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        // The last file is the analogy file, unless there is only one file.
+        let analogies_filename = if embeddings_filenames.len() > 1 {
+            embeddings_filenames
+                .pop()
+                .filter(|filename| filename != STDIO_FILENAME)
+        } else {
+            None
+        };
+        let analogy_format = matches
+            .value_of(ANALOGY_FORMAT)
+            .map(|f| {
+                AnalogyFormat::try_from(f).context(format!("Cannot parse analogy format: {}", f))
+            })
+            .transpose()?
+            .unwrap();
+        let embedding_format = matches
+            .value_of(EMBEDDING_FORMAT)
+            .map(|f| {
+                EmbeddingFormat::try_from(f)
+                    .context(format!("Cannot parse embedding format: {}", f))
+            })
+            .transpose()?
+            .unwrap();
         let n_threads = matches
-            .value_of("threads")
+            .value_of(THREADS)
             .map(|v| {
                 v.parse()
                     .context(format!("Cannot parse number of threads: {}", v))
             })
             .transpose()?
             .unwrap_or(num_cpus::get() / 2);
+        let objective = AnalogyObjective::parse_clap_matches(matches)?;
+        let output_filename = matches.value_of(OUTPUT).map(ToOwned::to_owned);
+        let output_format = ReportFormat::parse_clap_matches(matches)?;
+        let predictions_filename = matches.value_of(PREDICTIONS).map(ToOwned::to_owned);
+        let predictions_k = matches
+            .value_of(PREDICTIONS_K)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse number of predictions: {}", v))
+            })
+            .transpose()?
+            .unwrap();
+        let restrict = matches
+            .value_of(RESTRICT)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse vocabulary restriction: {}", v))
+            })
+            .transpose()?;
+        let bootstrap = matches
+            .value_of(BOOTSTRAP)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse number of bootstrap samples: {}", v))
+            })
+            .transpose()?;
+        let confidence = matches
+            .value_of(CONFIDENCE)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse confidence level: {}", v))
+            })
+            .transpose()?
+            .unwrap();
+        ensure!(
+            confidence > 0. && confidence < 1.,
+            "The confidence level must be between 0 and 1, was: {}",
+            confidence
+        );
+        let seed = matches
+            .value_of(SEED)
+            .map(|v| v.parse().context(format!("Cannot parse seed: {}", v)))
+            .transpose()?
+            .unwrap();
+
+        ensure!(
+            embeddings_filenames.len() == 1 || predictions_filename.is_none(),
+            "Predictions can only be written when a single model is evaluated"
+        );
 
         Ok(ComputeAccuracyApp {
             analogies_filename,
-            embeddings_filename,
+            analogy_format,
+            bootstrap,
+            case_insensitive: matches.is_present(CASE_INSENSITIVE),
+            confidence,
+            embeddings_filenames,
+            embedding_format,
             n_threads,
+            objective,
+            output_filename,
+            output_format,
+            overwrite: Overwrite::parse_clap_matches(matches),
+            predictions_filename,
+            predictions_k,
+            restrict,
+            seed,
+            skip_malformed: matches.is_present(SKIP_MALFORMED),
         })
     }
 
     fn run(&self) -> Result<()> {
-        ThreadPoolBuilder::new()
+        let output_filename = self.output_filename.as_deref().unwrap_or(STDIO_FILENAME);
+        self.check_output(output_filename)?;
+        if let Some(predictions_filename) = &self.predictions_filename {
+            self.check_output(predictions_filename)?;
+        }
+
+        let pool = ThreadPoolBuilder::new()
             .num_threads(self.n_threads)
-            .build_global()
-            .unwrap();
+            .build()
+            .context("Cannot create thread pool")?;
 
-        let embeddings =
-            read_embeddings_view(&self.embeddings_filename, EmbeddingFormat::FinalFusion)
-                .context("Cannot read embeddings")?;
+        let instances = match self.analogy_format {
+            AnalogyFormat::Bats => {
+                let dir = self
+                    .analogies_filename
+                    .as_ref()
+                    .context("BATS analogies cannot be read from standard input")?;
+                read_bats_analogies(Path::new(dir), self.skip_malformed)?
+            }
+            AnalogyFormat::Google => {
+                let analogies_file = Input::from(self.analogies_filename.as_ref());
+                let reader = analogies_file
+                    .buf_read()
+                    .context("Cannot open analogy file for reading")?;
+                read_analogies(
+                    reader,
+                    self.analogies_filename.as_deref().unwrap_or("<stdin>"),
+                    self.skip_malformed,
+                )?
+            }
+        };
 
-        let analogies_file = Input::from(self.analogies_filename.as_ref());
-        let reader = analogies_file
-            .buf_read()
-            .context("Cannot open analogy file for reading")?;
+        // Only compute more than the best answer when predictions are written.
+        let limit = if self.predictions_filename.is_some() {
+            self.predictions_k.max(1)
+        } else {
+            1
+        };
 
-        let instances = read_analogies(reader)?;
-        process_analogies(&embeddings, &instances);
+        // Evaluate the models one by one, so that only one model is in
+        // memory at a time.
+        let mut models = Vec::with_capacity(self.embeddings_filenames.len());
+        for filename in &self.embeddings_filenames {
+            let embeddings = read_embeddings_view(filename, self.embedding_format)
+                .context(format!("Cannot read embeddings: {}", filename))?;
+            let eval = Eval::new(
+                &embeddings,
+                self.objective,
+                self.restrict,
+                self.case_insensitive,
+                limit,
+            );
+            let predictions = pool.install(|| process_analogies(&eval, &instances));
+            let metadata = embeddings.metadata().map(|metadata| (**metadata).clone());
+            models.push((filename, metadata, predictions));
+        }
+
+        // Compare models on the analogies that every model covers.
+        if models.len() > 1 {
+            let covered = (0..instances.len())
+                .map(|idx| {
+                    models
+                        .iter()
+                        .all(|(_, _, predictions)| !predictions[idx].skipped)
+                })
+                .collect::<Vec<_>>();
+            for (_, _, predictions) in &mut models {
+                for (prediction, &covered) in predictions.iter_mut().zip(&covered) {
+                    if !covered {
+                        *prediction = Prediction::skipped();
+                    }
+                }
+            }
+        }
+
+        if let Some(predictions_filename) = &self.predictions_filename {
+            write_predictions(
+                predictions_filename,
+                self.overwrite,
+                &instances,
+                &models[0].2,
+            )?;
+        }
+
+        let results = models
+            .iter()
+            .map(|(filename, metadata, predictions)| {
+                let mut results = EvalResults::new(
+                    filename,
+                    metadata.clone(),
+                    &section_counts(&instances, predictions, self.objective.is_cosine()),
+                );
+                if let Some(n_samples) = self.bootstrap {
+                    // Every model uses the same seed, so that the models
+                    // are evaluated on the same samples.
+                    let mut rng = StdRng::seed_from_u64(self.seed);
+                    results.add_confidence_intervals(
+                        &instances,
+                        predictions,
+                        n_samples,
+                        self.confidence,
+                        &mut rng,
+                    );
+                }
+                results
+            })
+            .collect::<Vec<_>>();
+
+        let mcnemar = if models.len() == 2 {
+            Some(McNemar::new(&instances, &models[0].2, &models[1].2))
+        } else {
+            None
+        };
+
+        let mut writer = BufWriter::new(AtomicOutput::create(output_filename, self.overwrite)?);
+        match self.output_format {
+            ReportFormat::Json => {
+                let result = if results.len() == 1 {
+                    serde_json::to_writer_pretty(&mut writer, &results[0])
+                } else {
+                    serde_json::to_writer_pretty(
+                        &mut writer,
+                        &Comparison {
+                            models: &results,
+                            mcnemar: mcnemar.as_ref(),
+                        },
+                    )
+                };
+                result.context("Cannot write evaluation results")?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Text if results.len() == 1 => results[0]
+                .write_text(&mut writer)
+                .context("Cannot write evaluation results")?,
+            ReportFormat::Text => write_comparison_text(&mut writer, &results, mcnemar.as_ref())
+                .context("Cannot write evaluation results")?,
+        }
+
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("Cannot write evaluation results")?
+            .finish()
+    }
+}
+
+impl ComputeAccuracyApp {
+    /// Check that an output file does not overwrite any of the inputs.
+    fn check_output(&self, output_filename: &str) -> Result<()> {
+        for input_filename in self
+            .embeddings_filenames
+            .iter()
+            .chain(&self.analogies_filename)
+        {
+            check_output(input_filename, output_filename, self.overwrite)?;
+        }
 
         Ok(())
     }
@@ -97,141 +467,688 @@ struct Counts {
     n_correct: usize,
     n_instances: usize,
     n_skipped: usize,
-    sum_cos: f32,
+
+    /// Sum of the cosine similarities of the predicted answers, `None`
+    /// when the scores of the objective are not cosine similarities.
+    sum_cos: Option<f32>,
 }
 
-impl Default for Counts {
-    fn default() -> Self {
+impl Counts {
+    fn new(cosine: bool) -> Self {
         Counts {
             n_correct: 0,
             n_instances: 0,
             n_skipped: 0,
-            sum_cos: 0.,
+            sum_cos: if cosine { Some(0.) } else { None },
         }
     }
 }
 
-#[derive(Clone)]
 struct Eval<'a> {
     embeddings: &'a Embeddings<VocabWrap, StorageViewWrap>,
-    section_counts: Arc<Mutex<BTreeMap<String, Counts>>>,
+    objective: AnalogyObjective,
+    restrict: Option<usize>,
+    case_insensitive: bool,
+    limit: usize,
 }
 
 impl<'a> Eval<'a> {
-    fn new(embeddings: &'a Embeddings<VocabWrap, StorageViewWrap>) -> Self {
+    fn new(
+        embeddings: &'a Embeddings<VocabWrap, StorageViewWrap>,
+        objective: AnalogyObjective,
+        restrict: Option<usize>,
+        case_insensitive: bool,
+        limit: usize,
+    ) -> Self {
         Eval {
             embeddings,
-            section_counts: Arc::new(Mutex::new(BTreeMap::new())),
+            objective,
+            restrict,
+            case_insensitive,
+            limit,
         }
     }
 
     /// Evaluate an analogy.
-    fn eval_analogy(&self, instance: &Instance) {
-        // Skip instances where the to-be-predicted word is not in the
+    fn eval_analogy(&self, instance: &Instance) -> Prediction {
+        let query = [
+            self.resolve(&instance.query.0),
+            self.resolve(&instance.query.1),
+            self.resolve(&instance.query.2),
+        ];
+        let answers = instance
+            .answers
+            .iter()
+            .map(|answer| self.resolve(answer))
+            .collect::<Vec<_>>();
+
+        // Skip instances where none of the to-be-predicted words is in the
         // vocab. This is a shortcoming of the vocab size and not of the
-        // embedding model itself.
-        if self
-            .embeddings
-            .vocab()
-            .idx(&instance.answer)
-            .and_then(|idx| idx.word())
-            .is_none()
-        {
-            let mut section_counts = self.section_counts.lock().unwrap();
-            let counts = section_counts.entry(instance.section.clone()).or_default();
-            counts.n_skipped += 1;
-            return;
+        // embedding model itself. When the vocab is restricted, instances
+        // are also skipped if any of the query words is outside the
+        // restricted vocab, as in the word2vec compute-accuracy tool.
+        let answer_in_vocab = answers.iter().any(|answer| self.in_vocab(answer));
+        let skip = match self.restrict {
+            Some(_) => !(answer_in_vocab && query.iter().all(|word| self.in_vocab(word))),
+            None => !answer_in_vocab,
+        };
+        if skip {
+            return Prediction::skipped();
         }
 
         // If the model is not able to provide a query result, it is counted
         // as an error.
-        let (is_correct, cos) = self
-            .embeddings
-            .analogy([&instance.query.0, &instance.query.1, &instance.query.2], 1)
-            .map(|r| {
-                let result = r.first().unwrap();
-                (result.word() == instance.answer, result.cosine_similarity())
+        let predictions = self
+            .objective
+            .analogy(
+                self.embeddings,
+                [&query[0], &query[1], &query[2]],
+                [true, true, true],
+                self.limit,
+                self.restrict,
+            )
+            .map(|results| {
+                results
+                    .into_iter()
+                    .map(|result| ScoredWord {
+                        word: result.word.to_owned(),
+                        score: result.score,
+                    })
+                    .collect::<Vec<_>>()
             })
-            .unwrap_or((false, 0.));
+            .unwrap_or_default();
 
-        let mut section_counts = self.section_counts.lock().unwrap();
-        let counts = section_counts.entry(instance.section.clone()).or_default();
-        counts.n_instances += 1;
-        if is_correct {
-            counts.n_correct += 1;
+        let correct = predictions
+            .first()
+            .map(|best| {
+                answers
+                    .iter()
+                    .any(|answer| self.matches(&best.word, answer))
+            })
+            .unwrap_or(false);
+
+        Prediction {
+            predictions,
+            correct,
+            skipped: false,
         }
-        counts.sum_cos += cos;
     }
 
-    /// Print the accuracy for a section.
-    fn print_section_accuracy(&self, section: &str, counts: &Counts) {
-        if counts.n_instances == 0 {
-            eprintln!("{}: no evaluation instances", section);
-            return;
+    /// Get the form of a word that is used for lookups.
+    ///
+    /// With case-insensitive matching, a word that is not in the vocab
+    /// is replaced by its lowercased form if that is in the vocab.
+    fn resolve<'b>(&self, word: &'b str) -> Cow<'b, str> {
+        if !self.case_insensitive || self.word_idx(word).is_some() {
+            return Cow::Borrowed(word);
         }
 
-        println!(
-            "{}: {}/{} correct, accuracy: {:.2}, avg cos: {:1.2}, skipped: {}",
-            section,
-            counts.n_correct,
-            counts.n_instances,
-            (counts.n_correct as f64 / counts.n_instances as f64) * 100.,
-            (counts.sum_cos / counts.n_instances as f32),
-            counts.n_skipped,
-        );
+        let lowercased = word.to_lowercase();
+        if self.word_idx(&lowercased).is_some() {
+            Cow::Owned(lowercased)
+        } else {
+            Cow::Borrowed(word)
+        }
+    }
+
+    /// Check whether a predicted word matches the answer.
+    fn matches(&self, predicted: &str, answer: &str) -> bool {
+        if self.case_insensitive {
+            predicted.to_lowercase() == answer.to_lowercase()
+        } else {
+            predicted == answer
+        }
+    }
+
+    fn word_idx(&self, word: &str) -> Option<usize> {
+        self.embeddings.vocab().idx(word).and_then(|idx| idx.word())
+    }
+
+    /// Check whether a word is in the (restricted) vocab.
+    fn in_vocab(&self, word: &str) -> bool {
+        self.word_idx(word)
+            .map(|idx| self.restrict.map(|n| idx < n).unwrap_or(true))
+            .unwrap_or(false)
     }
 }
 
-impl<'a> Drop for Eval<'a> {
-    fn drop(&mut self) {
-        let section_counts = self.section_counts.lock().unwrap();
+/// Predictions for an analogy.
+#[derive(Serialize)]
+struct Prediction {
+    /// Best answers, from highest to lowest score.
+    predictions: Vec<ScoredWord>,
+    correct: bool,
 
-        // Print out counts for all sections.
-        for (section, counts) in section_counts.iter() {
-            self.print_section_accuracy(section, counts);
+    /// The analogy was skipped, because a word is not in the vocab.
+    skipped: bool,
+}
+
+impl Prediction {
+    fn skipped() -> Self {
+        Prediction {
+            predictions: Vec::new(),
+            correct: false,
+            skipped: true,
         }
+    }
+}
+
+#[derive(Serialize)]
+struct ScoredWord {
+    word: String,
+    score: f32,
+}
+
+/// Line of the predictions file.
+#[derive(Serialize)]
+struct PredictionRecord<'a> {
+    section: &'a str,
+    query: [&'a str; 3],
+    answers: &'a [String],
+    #[serde(flatten)]
+    prediction: &'a Prediction,
+}
+
+fn write_predictions(
+    filename: &str,
+    overwrite: Overwrite,
+    instances: &[Instance],
+    predictions: &[Prediction],
+) -> Result<()> {
+    let mut writer = BufWriter::new(AtomicOutput::create(filename, overwrite)?);
 
-        let n_correct = section_counts.values().map(|c| c.n_correct).sum::<usize>();
-        let n_instances = section_counts
+    for (instance, prediction) in instances.iter().zip(predictions) {
+        let record = PredictionRecord {
+            section: &instance.section,
+            query: [&instance.query.0, &instance.query.1, &instance.query.2],
+            answers: &instance.answers,
+            prediction,
+        };
+        serde_json::to_writer(&mut writer, &record)
+            .context(format!("Cannot write predictions to {}", filename))?;
+        writeln!(writer)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .context(format!("Cannot write predictions to {}", filename))?
+        .finish()
+}
+
+/// Count the correct, incorrect and skipped analogies for each section.
+///
+/// The scores of the predictions are only summed when `cosine` is true.
+fn section_counts(
+    instances: &[Instance],
+    predictions: &[Prediction],
+    cosine: bool,
+) -> BTreeMap<String, Counts> {
+    let mut section_counts: BTreeMap<String, Counts> = BTreeMap::new();
+    for (instance, prediction) in instances.iter().zip(predictions) {
+        let counts = section_counts
+            .entry(instance.section.clone())
+            .or_insert_with(|| Counts::new(cosine));
+        if prediction.skipped {
+            counts.n_skipped += 1;
+            continue;
+        }
+
+        counts.n_instances += 1;
+        if prediction.correct {
+            counts.n_correct += 1;
+        }
+        if let Some(sum_cos) = &mut counts.sum_cos {
+            *sum_cos += prediction
+                .predictions
+                .first()
+                .map(|best| best.score)
+                .unwrap_or(0.);
+        }
+    }
+
+    section_counts
+}
+
+/// Evaluation results of a model.
+#[derive(Serialize)]
+struct EvalResults {
+    embeddings: String,
+    metadata: Option<Value>,
+
+    /// Confidence level of the confidence intervals.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<f64>,
+
+    sections: Vec<SectionResults>,
+    total: Accuracy,
+}
+
+impl EvalResults {
+    fn new(
+        filename: &str,
+        metadata: Option<Value>,
+        section_counts: &BTreeMap<String, Counts>,
+    ) -> Self {
+        let sections = section_counts
+            .iter()
+            .map(|(section, counts)| SectionResults {
+                section: section.clone(),
+                accuracy: counts.into(),
+            })
+            .collect();
+
+        let total = Counts {
+            n_correct: section_counts.values().map(|c| c.n_correct).sum(),
+            n_instances: section_counts.values().map(|c| c.n_instances).sum(),
+            n_skipped: section_counts.values().map(|c| c.n_skipped).sum(),
+            sum_cos: section_counts.values().map(|c| c.sum_cos).sum(),
+        };
+
+        EvalResults {
+            embeddings: filename.to_owned(),
+            metadata,
+            confidence: None,
+            sections,
+            total: (&total).into(),
+        }
+    }
+
+    /// Add bootstrap confidence intervals to the accuracies.
+    fn add_confidence_intervals(
+        &mut self,
+        instances: &[Instance],
+        predictions: &[Prediction],
+        n_samples: usize,
+        confidence: f64,
+        rng: &mut impl Rng,
+    ) {
+        let mut section_correct: BTreeMap<&str, Vec<bool>> = BTreeMap::new();
+        for (instance, prediction) in instances.iter().zip(predictions) {
+            if !prediction.skipped {
+                section_correct
+                    .entry(&instance.section)
+                    .or_default()
+                    .push(prediction.correct);
+            }
+        }
+
+        for section in &mut self.sections {
+            let correct = section_correct
+                .get(section.section.as_str())
+                .map(Vec::as_slice)
+                .unwrap_or(&[]);
+            section.accuracy.confidence_interval =
+                bootstrap_accuracy(correct, n_samples, confidence, rng);
+        }
+
+        let correct = section_correct
             .values()
-            .map(|c| c.n_instances)
-            .sum::<usize>();
-        let n_skipped = section_counts.values().map(|c| c.n_skipped).sum::<usize>();
-        let n_instances_with_skipped = n_instances + n_skipped;
-        let cos = section_counts.values().map(|c| c.sum_cos).sum::<f32>();
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        self.total.confidence_interval = bootstrap_accuracy(&correct, n_samples, confidence, rng);
+        self.confidence = Some(confidence);
+    }
+
+    fn write_text(&self, write: &mut dyn Write) -> Result<()> {
+        // Print out counts for all sections.
+        for section in &self.sections {
+            let accuracy = &section.accuracy;
+            if accuracy.n_instances == 0 {
+                eprintln!("{}: no evaluation instances", section.section);
+                continue;
+            }
+
+            writeln!(
+                write,
+                "{}: {}/{} correct, accuracy: {:.2}{}{}, skipped: {}",
+                section.section,
+                accuracy.n_correct,
+                accuracy.n_instances,
+                accuracy.percentage(),
+                accuracy.interval_text(self.confidence),
+                accuracy.avg_cos_text(),
+                accuracy.n_skipped,
+            )?;
+        }
 
         // Print out overall counts.
-        println!(
-            "Total: {}/{} correct, accuracy: {:.2}, avg cos: {:1.2}",
-            n_correct,
-            n_instances,
-            (n_correct as f64 / n_instances as f64) * 100.,
-            (cos / n_instances as f32)
-        );
+        let total = &self.total;
+        writeln!(
+            write,
+            "Total: {}/{} correct, accuracy: {:.2}{}{}",
+            total.n_correct,
+            total.n_instances,
+            total.percentage(),
+            total.interval_text(self.confidence),
+            total.avg_cos_text(),
+        )?;
 
         // Print skip counts.
-        println!(
+        let n_instances_with_skipped = total.n_instances + total.n_skipped;
+        writeln!(
+            write,
             "Skipped: {}/{} ({}%)",
-            n_skipped,
+            total.n_skipped,
             n_instances_with_skipped,
-            (n_skipped as f64 / n_instances_with_skipped as f64) * 100.
+            (total.n_skipped as f64 / n_instances_with_skipped as f64) * 100.
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Evaluation results of several models.
+#[derive(Serialize)]
+struct Comparison<'a> {
+    models: &'a [EvalResults],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mcnemar: Option<&'a McNemar>,
+}
+
+/// Write the results of several models as a table with a column per model.
+fn write_comparison_text(
+    write: &mut dyn Write,
+    results: &[EvalResults],
+    mcnemar: Option<&McNemar>,
+) -> Result<()> {
+    // All models are evaluated on the same analogies, so they have the
+    // same sections.
+    let mut rows = results[0]
+        .sections
+        .iter()
+        .enumerate()
+        .map(|(idx, section)| {
+            let cells = results
+                .iter()
+                .map(|r| r.sections[idx].accuracy.cell_text())
+                .collect::<Vec<_>>();
+            (section.section.as_str(), cells)
+        })
+        .collect::<Vec<_>>();
+    rows.push((
+        "Total",
+        results.iter().map(|r| r.total.cell_text()).collect(),
+    ));
+
+    let section_width = rows
+        .iter()
+        .map(|(section, _)| section.chars().count())
+        .chain(Some("Section".len()))
+        .max()
+        .unwrap();
+    let column_widths = results
+        .iter()
+        .enumerate()
+        .map(|(idx, r)| {
+            rows.iter()
+                .map(|(_, cells)| cells[idx].chars().count())
+                .chain(Some(r.embeddings.chars().count()))
+                .max()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    write!(write, "{:<width$}", "Section", width = section_width)?;
+    for (r, &width) in results.iter().zip(&column_widths) {
+        write!(write, "  {:>width$}", r.embeddings, width = width)?;
+    }
+    writeln!(write)?;
+
+    for (section, cells) in &rows {
+        write!(write, "{:<width$}", section, width = section_width)?;
+        for (cell, &width) in cells.iter().zip(&column_widths) {
+            write!(write, "  {:>width$}", cell, width = width)?;
+        }
+        writeln!(write)?;
+    }
+
+    // Models are compared on the same analogies, so the skip counts are
+    // the same for every model.
+    let total = &results[0].total;
+    let n_instances_with_skipped = total.n_instances + total.n_skipped;
+    writeln!(
+        write,
+        "Skipped: {}/{} ({}%)",
+        total.n_skipped,
+        n_instances_with_skipped,
+        (total.n_skipped as f64 / n_instances_with_skipped as f64) * 100.
+    )?;
+
+    if let Some(mcnemar) = mcnemar {
+        writeln!(
+            write,
+            "McNemar's test, {} vs. {}:",
+            results[0].embeddings, results[1].embeddings
+        )?;
+        for section in &mcnemar.sections {
+            writeln!(write, "{}: {}", section.section, section.test)?;
+        }
+        writeln!(write, "Total: {}", mcnemar.total)?;
+    }
+
+    Ok(())
+}
+
+/// McNemar's test for the difference between the accuracies of two models.
+#[derive(Serialize)]
+struct McNemar {
+    sections: Vec<SectionMcNemar>,
+    total: McNemarTest,
+}
+
+impl McNemar {
+    fn new(
+        instances: &[Instance],
+        predictions1: &[Prediction],
+        predictions2: &[Prediction],
+    ) -> Self {
+        let mut section_counts: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+        for ((instance, prediction1), prediction2) in
+            instances.iter().zip(predictions1).zip(predictions2)
+        {
+            let counts = section_counts.entry(&instance.section).or_default();
+            if prediction1.skipped || prediction2.skipped {
+                continue;
+            }
+
+            match (prediction1.correct, prediction2.correct) {
+                (true, false) => counts.0 += 1,
+                (false, true) => counts.1 += 1,
+                _ => (),
+            }
+        }
+
+        let only_first = section_counts.values().map(|counts| counts.0).sum();
+        let only_second = section_counts.values().map(|counts| counts.1).sum();
+
+        McNemar {
+            sections: section_counts
+                .into_iter()
+                .map(|(section, (only_first, only_second))| SectionMcNemar {
+                    section: section.to_owned(),
+                    test: McNemarTest::new(only_first, only_second),
+                })
+                .collect(),
+            total: McNemarTest::new(only_first, only_second),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SectionMcNemar {
+    section: String,
+    #[serde(flatten)]
+    test: McNemarTest,
+}
+
+#[derive(Serialize)]
+struct McNemarTest {
+    /// Number of analogies that only the first model answered correctly.
+    only_first: usize,
+
+    /// Number of analogies that only the second model answered correctly.
+    only_second: usize,
+
+    p_value: f64,
+}
+
+impl McNemarTest {
+    fn new(only_first: usize, only_second: usize) -> Self {
+        McNemarTest {
+            only_first,
+            only_second,
+            p_value: mcnemar_exact(only_first, only_second),
+        }
+    }
+}
+
+impl fmt::Display for McNemarTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "only first correct: {}, only second correct: {}, p: {:.4}",
+            self.only_first, self.only_second, self.p_value
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct SectionResults {
+    section: String,
+    #[serde(flatten)]
+    accuracy: Accuracy,
+}
+
+#[derive(Serialize)]
+struct Accuracy {
+    n_correct: usize,
+    n_instances: usize,
+    n_skipped: usize,
+
+    /// Fraction of the instances that was answered correctly.
+    accuracy: Option<f64>,
+
+    /// Average cosine similarity of the predicted answers, omitted
+    /// when the objective does not score answers by cosine similarity.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_cos: Option<f32>,
+
+    /// Bootstrap confidence interval of the accuracy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence_interval: Option<(f64, f64)>,
+}
+
+impl Accuracy {
+    fn percentage(&self) -> f64 {
+        self.accuracy.unwrap_or(f64::NAN) * 100.
+    }
+
+    /// Format the accuracy for a table cell.
+    fn cell_text(&self) -> String {
+        if self.accuracy.is_none() {
+            return "-".to_string();
+        }
+
+        let mut cell = format!(
+            "{:.2} ({}/{})",
+            self.percentage(),
+            self.n_correct,
+            self.n_instances
         );
+        if let Some((lower, upper)) = self.confidence_interval {
+            cell.push_str(&format!(" [{:.2}, {:.2}]", lower * 100., upper * 100.));
+        }
+
+        cell
+    }
+
+    /// Format the average cosine similarity, if any.
+    fn avg_cos_text(&self) -> String {
+        match self.avg_cos {
+            Some(avg_cos) => format!(", avg cos: {:1.2}", avg_cos),
+            None => String::new(),
+        }
+    }
+
+    /// Format the confidence interval, if any.
+    fn interval_text(&self, confidence: Option<f64>) -> String {
+        match (confidence, self.confidence_interval) {
+            (Some(confidence), Some((lower, upper))) => format!(
+                ", {}% CI: [{:.2}, {:.2}]",
+                confidence * 100.,
+                lower * 100.,
+                upper * 100.
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+impl From<&Counts> for Accuracy {
+    fn from(counts: &Counts) -> Self {
+        let (accuracy, avg_cos) = if counts.n_instances == 0 {
+            (None, None)
+        } else {
+            (
+                Some(counts.n_correct as f64 / counts.n_instances as f64),
+                counts
+                    .sum_cos
+                    .map(|sum_cos| sum_cos / counts.n_instances as f32),
+            )
+        };
+
+        Accuracy {
+            n_correct: counts.n_correct,
+            n_instances: counts.n_instances,
+            n_skipped: counts.n_skipped,
+            accuracy,
+            avg_cos,
+            confidence_interval: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AnalogyFormat {
+    Bats,
+    Google,
+}
+
+impl TryFrom<&str> for AnalogyFormat {
+    type Error = Error;
+
+    fn try_from(format: &str) -> Result<Self> {
+        match format {
+            "bats" => Ok(AnalogyFormat::Bats),
+            "google" => Ok(AnalogyFormat::Google),
+            unknown => Err(anyhow!("Unknown analogy format: {}", unknown)),
+        }
     }
 }
 
 struct Instance {
     section: String,
     query: (String, String, String),
-    answer: String,
+
+    /// Acceptable answers, a prediction is correct if it is any of them.
+    answers: Vec<String>,
 }
 
-fn read_analogies(reader: impl BufRead) -> Result<Vec<Instance>> {
+fn read_analogies(
+    reader: impl BufRead,
+    filename: &str,
+    skip_malformed: bool,
+) -> Result<Vec<Instance>> {
     let mut section = String::new();
 
     let mut instances = Vec::new();
+    let mut n_malformed = 0;
 
-    for line in reader.lines() {
-        let line = line.context("Cannot read line")?;
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line_no = line_idx + 1;
+        let line = line.context(format!("Cannot read line {} of {}", line_no, filename))?;
 
         if line.starts_with(": ") {
             section = line.chars().skip(2).collect::<String>();
@@ -239,6 +1156,25 @@ fn read_analogies(reader: impl BufRead) -> Result<Vec<Instance>> {
         }
 
         let quadruple: Vec<_> = line.split_whitespace().collect();
+        if quadruple.is_empty() {
+            continue;
+        }
+
+        if quadruple.len() != 4 {
+            let msg = format!(
+                "{}:{}: expected 4 words, found {}: {}",
+                filename,
+                line_no,
+                quadruple.len(),
+                line.trim()
+            );
+            if skip_malformed {
+                eprintln!("{}, skipping", msg);
+                n_malformed += 1;
+                continue;
+            }
+            bail!(msg);
+        }
 
         instances.push(Instance {
             section: section.clone(),
@@ -247,24 +1183,194 @@ fn read_analogies(reader: impl BufRead) -> Result<Vec<Instance>> {
                 quadruple[1].to_owned(),
                 quadruple[2].to_owned(),
             ),
-            answer: quadruple[3].to_owned(),
+            answers: vec![quadruple[3].to_owned()],
         });
     }
 
+    if n_malformed != 0 {
+        eprintln!("Skipped {} malformed line(s) in {}", n_malformed, filename);
+    }
+
     Ok(instances)
 }
 
-fn process_analogies(embeddings: &Embeddings<VocabWrap, StorageViewWrap>, instances: &[Instance]) {
+/// Read analogies from a directory in the Bigger Analogy Test Set format.
+///
+/// Every file in the directory (or its subdirectories) is a category with
+/// word pairs `a\tb1/b2`, where `b1` and `b2` are acceptable answers. An
+/// analogy is constructed for every two different pairs of a category.
+/// The category is the file name without extension.
+fn read_bats_analogies(dir: &Path, skip_malformed: bool) -> Result<Vec<Instance>> {
+    let mut filenames = Vec::new();
+    find_files(dir, &mut filenames)
+        .context(format!("Cannot read BATS directory: {}", dir.display()))?;
+    filenames.sort();
+
+    let mut instances = Vec::new();
+    for filename in filenames {
+        let category = filename
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let f = File::open(&filename)
+            .context(format!("Cannot open BATS file: {}", filename.display()))?;
+        let pairs = read_bats_pairs(
+            BufReader::new(f),
+            &filename.display().to_string(),
+            skip_malformed,
+        )?;
+
+        for (idx1, (a, b)) in pairs.iter().enumerate() {
+            for (idx2, (c, d)) in pairs.iter().enumerate() {
+                if idx1 == idx2 {
+                    continue;
+                }
+
+                instances.push(Instance {
+                    section: category.clone(),
+                    query: (a.clone(), b[0].clone(), c.clone()),
+                    answers: d.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(instances)
+}
+
+/// Recursively find the files in a directory.
+fn find_files(dir: &Path, filenames: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_files(&path, filenames)?;
+        } else if path.extension().map(|ext| ext == "txt").unwrap_or(false) {
+            filenames.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the word pairs of a BATS category.
+fn read_bats_pairs(
+    reader: impl BufRead,
+    filename: &str,
+    skip_malformed: bool,
+) -> Result<Vec<(String, Vec<String>)>> {
+    let mut pairs = Vec::new();
+    let mut n_malformed = 0;
+
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line_no = line_idx + 1;
+        let line = line.context(format!("Cannot read line {} of {}", line_no, filename))?;
+
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        let answers = fields
+            .get(1)
+            .map(|answers| {
+                answers
+                    .split('/')
+                    .filter(|answer| !answer.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        if fields.len() != 2 || answers.is_empty() {
+            let msg = format!(
+                "{}:{}: expected a word and its counterparts (a b1/b2), found: {}",
+                filename,
+                line_no,
+                line.trim()
+            );
+            if skip_malformed {
+                eprintln!("{}, skipping", msg);
+                n_malformed += 1;
+                continue;
+            }
+            bail!(msg);
+        }
+
+        pairs.push((fields[0].to_owned(), answers));
+    }
+
+    if n_malformed != 0 {
+        eprintln!("Skipped {} malformed line(s) in {}", n_malformed, filename);
+    }
+
+    Ok(pairs)
+}
+
+fn process_analogies(eval: &Eval, instances: &[Instance]) -> Vec<Prediction> {
     let pb = ProgressBar::new(instances.len() as u64);
     pb.set_style(
         ProgressStyle::default_bar().template("{bar:30} {percent}% {msg} ETA: {eta_precise}"),
     );
-    let eval = Eval::new(embeddings);
-    instances.par_iter().enumerate().for_each(|(i, instance)| {
-        if i % 50 == 0 {
-            pb.inc(50);
-        }
-        eval.eval_analogy(instance)
-    });
+    let predictions = instances
+        .par_iter()
+        .enumerate()
+        .map(|(i, instance)| {
+            if i % 50 == 0 {
+                pb.inc(50);
+            }
+            eval.eval_analogy(instance)
+        })
+        .collect();
     pb.finish();
+
+    predictions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_bats_pairs;
+
+    fn pair(word: &str, answers: &[&str]) -> (String, Vec<String>) {
+        (
+            word.to_owned(),
+            answers.iter().map(|&answer| answer.to_owned()).collect(),
+        )
+    }
+
+    #[test]
+    fn reads_pairs_with_alternative_answers() {
+        let pairs = read_bats_pairs(
+            "berlin\tgermany\n\nparis france/french\nrome italy//\n".as_bytes(),
+            "capitals.txt",
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                pair("berlin", &["germany"]),
+                pair("paris", &["france", "french"]),
+                pair("rome", &["italy"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for data in &["berlin\n", "berlin germany deutschland\n", "berlin /\n"] {
+            let err = read_bats_pairs(data.as_bytes(), "capitals.txt", false).unwrap_err();
+            assert!(err.to_string().starts_with("capitals.txt:1:"));
+        }
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let pairs = read_bats_pairs(
+            "berlin\nparis france\nrome italy it\n".as_bytes(),
+            "capitals.txt",
+            true,
+        )
+        .unwrap();
+        assert_eq!(pairs, vec![pair("paris", &["france"])]);
+    }
 }
@@ -0,0 +1,315 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::io::BufRead;
+
+use anyhow::{ensure, Context, Result};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use finalfusion::prelude::*;
+use finalfusion::vocab::Vocab;
+use ndarray::Array1;
+use stdinout::Input;
+
+use crate::io::{read_embeddings_view, EmbeddingFormat};
+use crate::similarity::SimilarityMeasure;
+use crate::FinalfusionApp;
+
+static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
+    AppSettings::DontCollapseArgsInUsage,
+    AppSettings::UnifiedHelpMessage,
+];
+
+// Option constants
+static EMBEDDING_FORMAT: &str = "embedding_format";
+static SUBWORDS: &str = "subwords";
+
+// Argument constants
+static EMBEDDINGS: &str = "EMBEDDINGS";
+static PAIRS: &str = "PAIRS";
+
+pub struct ComputeCorrelationApp {
+    embeddings_filename: String,
+    embedding_format: EmbeddingFormat,
+    pairs_filename: Option<String>,
+    similarity: SimilarityMeasure,
+    subwords: bool,
+}
+
+impl FinalfusionApp for ComputeCorrelationApp {
+    fn app() -> App<'static, 'static> {
+        App::new("compute-correlation")
+            .about("Compute the correlation with word pair similarity judgements")
+            .settings(DEFAULT_CLAP_SETTINGS)
+            .arg(
+                Arg::with_name(EMBEDDING_FORMAT)
+                    .short("f")
+                    .value_name("FORMAT")
+                    .takes_value(true)
+                    .possible_values(&[
+                        "auto",
+                        "fasttext",
+                        "finalfusion",
+                        "finalfusion_mmap",
+                        "floret",
+                        "text",
+                        "textdims",
+                        "word2vec",
+                    ])
+                    .default_value("auto"),
+            )
+            .arg(SimilarityMeasure::new_clap_arg())
+            .arg(
+                Arg::with_name(SUBWORDS)
+                    .long("subwords")
+                    .help("Use subword embeddings for unknown words, rather than skipping pairs"),
+            )
+            .arg(
+                Arg::with_name(EMBEDDINGS)
+                    .help("Embedding file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(PAIRS)
+                    .help("Word pairs with scores (word1 word2 score)")
+                    .index(2),
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self> {
+        let embeddings_filename = matches.value_of(EMBEDDINGS).unwrap().to_owned();
+        let pairs_filename = matches.value_of(PAIRS).map(ToOwned::to_owned);
+        let embedding_format = matches
+            .value_of(EMBEDDING_FORMAT)
+            .map(|f| {
+                EmbeddingFormat::try_from(f)
+                    .context(format!("Cannot parse embedding format: {}", f))
+            })
+            .transpose()?
+            .unwrap();
+        let similarity = SimilarityMeasure::parse_clap_matches(matches)?;
+        ensure!(
+            similarity != SimilarityMeasure::Csls,
+            "The csls similarity measure is not supported for word pairs"
+        );
+
+        Ok(ComputeCorrelationApp {
+            embeddings_filename,
+            embedding_format,
+            pairs_filename,
+            similarity,
+            subwords: matches.is_present(SUBWORDS),
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let embeddings = read_embeddings_view(&self.embeddings_filename, self.embedding_format)
+            .context("Cannot read embeddings")?;
+
+        let pairs_file = Input::from(self.pairs_filename.as_ref());
+        let reader = pairs_file
+            .buf_read()
+            .context("Cannot open word pair file for reading")?;
+        let pairs = read_pairs(reader, self.pairs_filename.as_deref().unwrap_or("<stdin>"))?;
+
+        let mut gold = Vec::with_capacity(pairs.len());
+        let mut predicted = Vec::with_capacity(pairs.len());
+        for pair in &pairs {
+            let (embedding1, embedding2) = match (
+                self.embedding(&embeddings, &pair.word1),
+                self.embedding(&embeddings, &pair.word2),
+            ) {
+                (Some(embedding1), Some(embedding2)) => (embedding1, embedding2),
+                _ => continue,
+            };
+
+            gold.push(pair.score as f64);
+            predicted.push(
+                self.similarity
+                    .similarity(embedding1.view(), embedding2.view()) as f64,
+            );
+        }
+
+        println!(
+            "Covered: {}/{} ({:.2}%)",
+            gold.len(),
+            pairs.len(),
+            (gold.len() as f64 / pairs.len() as f64) * 100.
+        );
+        println!(
+            "Pearson: {}",
+            format_correlation(pearson(&gold, &predicted))
+        );
+        println!(
+            "Spearman: {}",
+            format_correlation(spearman(&gold, &predicted))
+        );
+
+        Ok(())
+    }
+}
+
+impl ComputeCorrelationApp {
+    /// Look up the unnormalized embedding of a word.
+    ///
+    /// Subword embeddings are only used for unknown words when subword
+    /// back-off is enabled.
+    fn embedding(
+        &self,
+        embeddings: &Embeddings<VocabWrap, StorageViewWrap>,
+        word: &str,
+    ) -> Option<Array1<f32>> {
+        let idx = embeddings.vocab().idx(word)?;
+        if !self.subwords && idx.word().is_none() {
+            return None;
+        }
+
+        embeddings
+            .embedding_with_norm(word)
+            .map(|embedding| embedding.embedding.into_owned() * embedding.norm)
+    }
+}
+
+struct Pair {
+    word1: String,
+    word2: String,
+    score: f32,
+}
+
+fn read_pairs(reader: impl BufRead, filename: &str) -> Result<Vec<Pair>> {
+    let mut pairs = Vec::new();
+
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line_no = line_idx + 1;
+        let line = line.context(format!("Cannot read line {} of {}", line_no, filename))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<_> = line.split_whitespace().collect();
+        ensure!(
+            fields.len() == 3,
+            "{}:{}: expected 3 fields (word1 word2 score), found {}",
+            filename,
+            line_no,
+            fields.len()
+        );
+
+        let score = fields[2].parse().context(format!(
+            "{}:{}: cannot parse score: {}",
+            filename, line_no, fields[2]
+        ))?;
+
+        pairs.push(Pair {
+            word1: fields[0].to_owned(),
+            word2: fields[1].to_owned(),
+            score,
+        });
+    }
+
+    Ok(pairs)
+}
+
+fn format_correlation(correlation: Option<f64>) -> String {
+    match correlation {
+        Some(correlation) => format!("{:.4}", correlation),
+        None => "undefined".to_string(),
+    }
+}
+
+/// Pearson's correlation coefficient.
+///
+/// Returns `None` when there are fewer than two values or when one of
+/// the variables is constant.
+fn pearson(x: &[f64], y: &[f64]) -> Option<f64> {
+    assert_eq!(x.len(), y.len());
+
+    if x.len() < 2 {
+        return None;
+    }
+
+    let n = x.len() as f64;
+    let mean_x = x.iter().sum::<f64>() / n;
+    let mean_y = y.iter().sum::<f64>() / n;
+
+    let mut cov = 0.;
+    let mut var_x = 0.;
+    let mut var_y = 0.;
+    for (&x, &y) in x.iter().zip(y) {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x).powi(2);
+        var_y += (y - mean_y).powi(2);
+    }
+
+    if var_x == 0. || var_y == 0. {
+        return None;
+    }
+
+    Some(cov / (var_x.sqrt() * var_y.sqrt()))
+}
+
+/// Spearman's rank correlation coefficient.
+fn spearman(x: &[f64], y: &[f64]) -> Option<f64> {
+    pearson(&ranks(x), &ranks(y))
+}
+
+/// Rank values, ties get the average of their ranks.
+fn ranks(values: &[f64]) -> Vec<f64> {
+    let mut indices: Vec<_> = (0..values.len()).collect();
+    indices.sort_by(|&a, &b| values[a].partial_cmp(&values[b]).unwrap_or(Ordering::Equal));
+
+    let mut ranks = vec![0.; values.len()];
+    let mut start = 0;
+    while start < indices.len() {
+        let mut end = start + 1;
+        while end < indices.len() && values[indices[end]] == values[indices[start]] {
+            end += 1;
+        }
+
+        // Ranks are 1-based, tied values get the mean of ranks start+1..=end.
+        let rank = (start + end + 1) as f64 / 2.;
+        for &idx in &indices[start..end] {
+            ranks[idx] = rank;
+        }
+
+        start = end;
+    }
+
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ranks, spearman};
+
+    #[test]
+    fn ranks_are_one_based() {
+        assert_eq!(ranks(&[0.3, 0.1, 0.2]), vec![3., 1., 2.]);
+        assert_eq!(ranks(&[]), Vec::<f64>::new());
+    }
+
+    #[test]
+    fn ranks_average_ties() {
+        assert_eq!(ranks(&[5., 6., 7., 8., 7.]), vec![1., 2., 3.5, 5., 3.5]);
+        assert_eq!(ranks(&[1., 1., 1.]), vec![2., 2., 2.]);
+    }
+
+    #[test]
+    fn spearman_monotonic() {
+        let x = [1., 2., 3., 4., 5.];
+        assert!((spearman(&x, &[1., 4., 9., 16., 25.]).unwrap() - 1.).abs() < 1e-12);
+        assert!((spearman(&x, &[5., 3., 1., 0., -7.]).unwrap() + 1.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn spearman_with_ties() {
+        let rho = spearman(&[1., 2., 3., 4., 5.], &[5., 6., 7., 8., 7.]).unwrap();
+        assert!((rho - 8. / 95f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn spearman_undefined() {
+        assert_eq!(spearman(&[1.], &[2.]), None);
+        assert_eq!(spearman(&[1., 2., 3.], &[4., 4., 4.]), None);
+    }
+}
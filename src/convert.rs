@@ -10,7 +10,11 @@ use finalfusion::metadata::Metadata;
 use finalfusion::prelude::*;
 use toml::Value;
 
-use crate::io::{write_embeddings, EmbeddingFormat};
+use crate::io::{
+    check_output, write_embeddings, EmbeddingFormat, EmbeddingsReader, EmbeddingsWriter, Overwrite,
+    STDIO_FILENAME,
+};
+use crate::streaming::{convert_streaming, count_text_embeddings, is_streamable, requires_count};
 use crate::FinalfusionApp;
 
 // Option constants
@@ -31,6 +35,7 @@ pub struct ConvertApp {
     input_format: EmbeddingFormat,
     output_format: EmbeddingFormat,
     lossy: bool,
+    overwrite: Overwrite,
     unnormalize: bool,
 }
 
@@ -40,13 +45,13 @@ impl FinalfusionApp for ConvertApp {
             .about("Convert between embedding formats")
             .arg(
                 Arg::with_name(INPUT)
-                    .help("finalfusion model")
+                    .help("Input embeddings (- for standard input)")
                     .index(1)
                     .required(true),
             )
             .arg(
                 Arg::with_name(OUTPUT)
-                    .help("Output file")
+                    .help("Output file (- for standard output)")
                     .index(2)
                     .required(true),
             )
@@ -57,6 +62,7 @@ impl FinalfusionApp for ConvertApp {
                     .value_name("FORMAT")
                     .takes_value(true)
                     .possible_values(&[
+                        "auto",
                         "fasttext",
                         "finalfusion",
                         "floret",
@@ -64,8 +70,9 @@ impl FinalfusionApp for ConvertApp {
                         "textdims",
                         "word2vec",
                     ])
-                    .default_value("word2vec"),
+                    .default_value("auto"),
             )
+            .arg(Overwrite::new_clap_arg())
             .arg(
                 Arg::with_name(LOSSY)
                     .long("lossy")
@@ -86,8 +93,19 @@ impl FinalfusionApp for ConvertApp {
                     .long("to")
                     .value_name("FORMAT")
                     .takes_value(true)
-                    .possible_values(&["finalfusion", "text", "textdims", "word2vec"])
-                    .default_value("finalfusion"),
+                    .possible_values(&[
+                        "fasttext",
+                        "finalfusion",
+                        "floret",
+                        "text",
+                        "textdims",
+                        "word2vec",
+                    ])
+                    .default_value("finalfusion")
+                    .help(
+                        "Output format (converting text to textdims or word2vec \
+                         reads the input twice to count the embeddings)",
+                    ),
             )
             .arg(
                 Arg::with_name(UNNORMALIZE)
@@ -125,11 +143,14 @@ impl FinalfusionApp for ConvertApp {
             output_format,
             metadata_filename,
             lossy: matches.is_present(LOSSY),
+            overwrite: Overwrite::parse_clap_matches(matches),
             unnormalize: matches.is_present(UNNORMALIZE),
         })
     }
 
     fn run(&self) -> Result<()> {
+        check_output(&self.input_filename, &self.output_filename, self.overwrite)?;
+
         let metadata = self
             .metadata_filename
             .as_ref()
@@ -137,7 +158,22 @@ impl FinalfusionApp for ConvertApp {
             .transpose()?
             .map(Metadata::new);
 
-        let mut embeddings = read_embeddings(&self.input_filename, self.input_format, self.lossy)?;
+        let (reader, input_format) =
+            EmbeddingsReader::open(&self.input_filename, self.input_format)?;
+
+        // Conversions between text-based formats are done one embedding at
+        // a time. This is not possible when the number of embeddings is
+        // needed upfront and the input cannot be read twice.
+        if is_streamable(input_format)
+            && is_streamable(self.output_format)
+            && !(requires_count(input_format, self.output_format)
+                && self.input_filename == STDIO_FILENAME)
+        {
+            return self.convert_streaming(reader, input_format);
+        }
+
+        let mut embeddings =
+            read_embeddings(reader, input_format, &self.input_filename, self.lossy)?;
 
         // Overwrite metadata if provided, otherwise retain existing metadata.
         if metadata.is_some() {
@@ -149,11 +185,49 @@ impl FinalfusionApp for ConvertApp {
             &self.output_filename,
             self.output_format,
             self.unnormalize,
+            self.overwrite,
         )
         .context("Cannot write embeddings")
     }
 }
 
+impl ConvertApp {
+    fn convert_streaming(
+        &self,
+        reader: EmbeddingsReader,
+        input_format: EmbeddingFormat,
+    ) -> Result<()> {
+        let n_embeddings = if requires_count(input_format, self.output_format) {
+            let (count_reader, _) = EmbeddingsReader::open(&self.input_filename, input_format)?;
+            Some(
+                count_text_embeddings(count_reader, self.lossy).context(format!(
+                    "Cannot count embeddings in {}",
+                    self.input_filename
+                ))?,
+            )
+        } else {
+            None
+        };
+
+        let mut writer = EmbeddingsWriter::create(&self.output_filename, self.overwrite)?;
+        convert_streaming(
+            reader,
+            input_format,
+            &mut writer,
+            self.output_format,
+            n_embeddings,
+            self.lossy,
+            self.unnormalize,
+        )
+        .context(format!(
+            "Cannot convert {} embeddings from {}",
+            input_format, self.input_filename
+        ))?;
+
+        writer.finish()
+    }
+}
+
 fn read_metadata(filename: impl AsRef<str>) -> Result<Value> {
     let f = File::open(filename.as_ref())
         .context(format!("Cannot open metadata file: {}", filename.as_ref()))?;
@@ -169,19 +243,18 @@ fn read_metadata(filename: impl AsRef<str>) -> Result<Value> {
 }
 
 fn read_embeddings(
-    filename: &str,
+    mut reader: EmbeddingsReader,
     embedding_format: EmbeddingFormat,
+    filename: &str,
     lossy: bool,
 ) -> Result<Embeddings<VocabWrap, StorageWrap>> {
-    let f = File::open(filename).context(format!("Cannot open embeddings file: {}", filename))?;
-    let mut reader = BufReader::new(f);
-
     use self::EmbeddingFormat::*;
     match (embedding_format, lossy) {
+        (Auto, _) => unreachable!(),
         (FastText, true) => ReadFastText::read_fasttext_lossy(&mut reader).map(Embeddings::into),
         (FastText, false) => ReadFastText::read_fasttext(&mut reader).map(Embeddings::into),
         (FinalFusion, _) => ReadEmbeddings::read_embeddings(&mut reader),
-        (FinalFusionMmap, _) => MmapEmbeddings::mmap_embeddings(&mut reader),
+        (FinalFusionMmap, _) => MmapEmbeddings::mmap_embeddings(reader.file_mut(filename)?),
         (Floret, _) => ReadFloretText::read_floret_text(&mut reader).map(Embeddings::into),
         (Word2Vec, true) => {
             ReadWord2Vec::read_word2vec_binary_lossy(&mut reader).map(Embeddings::into)
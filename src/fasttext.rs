@@ -0,0 +1,272 @@
+use std::io::Write;
+
+use anyhow::{bail, ensure, Context, Result};
+use finalfusion::prelude::*;
+use finalfusion::storage::Storage;
+use finalfusion::subword::BucketIndexer;
+use finalfusion::vocab::{FastTextSubwordVocab, SubwordIndices, Vocab};
+use ndarray::Array1;
+
+const FASTTEXT_MAGIC: i32 = 793_712_314;
+const FASTTEXT_VERSION: i32 = 12;
+
+// Training hyperparameters are not stored in finalfusion files, so the
+// fastText defaults for skipgram models are written.
+const FASTTEXT_WS: i32 = 5;
+const FASTTEXT_EPOCH: i32 = 5;
+const FASTTEXT_MIN_COUNT: i32 = 5;
+const FASTTEXT_NEG: i32 = 5;
+const FASTTEXT_WORD_NGRAMS: i32 = 1;
+const FASTTEXT_LOSS_NS: i32 = 2;
+const FASTTEXT_MODEL_SKIPGRAM: i32 = 2;
+const FASTTEXT_LR_UPDATE_RATE: i32 = 100;
+const FASTTEXT_SAMPLING_THRESHOLD: f64 = 1e-4;
+
+/// Write embeddings as a fastText model.
+///
+/// `finalfusion::compat::fasttext::WriteFastText` only writes the input
+/// matrix, which finalfusion can read, but the official fastText tools
+/// fail to load such files. This writer also writes an all-zero output
+/// matrix, so that the files can be loaded by fastText.
+pub trait WriteFastTextModel {
+    fn write_fasttext_model(&self, write: &mut dyn Write) -> Result<()>;
+}
+
+impl WriteFastTextModel for Embeddings<VocabWrap, StorageWrap> {
+    fn write_fasttext_model(&self, write: &mut dyn Write) -> Result<()> {
+        let vocab = match self.vocab() {
+            VocabWrap::FastTextSubwordVocab(vocab) => vocab,
+            VocabWrap::SimpleVocab(_) => bail!(
+                "Cannot write embeddings without subwords in fastText format, \
+                 only fastText subword vocabularies are supported"
+            ),
+            VocabWrap::ExplicitSubwordVocab(_) => bail!(
+                "Cannot write embeddings with explicit n-grams in fastText format, \
+                 only fastText subword vocabularies are supported"
+            ),
+            VocabWrap::BucketSubwordVocab(_) => bail!(
+                "Cannot write embeddings with finalfusion bucket hashing in fastText format, \
+                 only fastText subword vocabularies are supported"
+            ),
+            VocabWrap::FloretSubwordVocab(_) => bail!(
+                "Cannot write embeddings with floret hashing in fastText format, \
+                 only fastText subword vocabularies are supported"
+            ),
+        };
+
+        let (n_rows, dims) = self.storage().shape();
+        let n_buckets = vocab.indexer().buckets();
+        ensure!(
+            n_rows == vocab.words_len() + n_buckets,
+            "Storage has {} rows, but the vocabulary has {} words and {} buckets",
+            n_rows,
+            vocab.words_len(),
+            n_buckets
+        );
+
+        write_header(write, vocab, dims).context("Cannot write fastText header")?;
+        write_dictionary(write, vocab).context("Cannot write fastText dictionary")?;
+        write_input_matrix(write, self, vocab).context("Cannot write fastText input matrix")?;
+        write_output_matrix(write, vocab.words_len(), dims)
+            .context("Cannot write fastText output matrix")
+    }
+}
+
+fn write_header(write: &mut dyn Write, vocab: &FastTextSubwordVocab, dims: usize) -> Result<()> {
+    write_i32(write, FASTTEXT_MAGIC)?;
+    write_i32(write, FASTTEXT_VERSION)?;
+
+    write_i32(write, dims as i32)?;
+    write_i32(write, FASTTEXT_WS)?;
+    write_i32(write, FASTTEXT_EPOCH)?;
+    write_i32(write, FASTTEXT_MIN_COUNT)?;
+    write_i32(write, FASTTEXT_NEG)?;
+    write_i32(write, FASTTEXT_WORD_NGRAMS)?;
+    write_i32(write, FASTTEXT_LOSS_NS)?;
+    write_i32(write, FASTTEXT_MODEL_SKIPGRAM)?;
+    write_i32(write, vocab.indexer().buckets() as i32)?;
+    write_i32(write, vocab.min_n() as i32)?;
+    write_i32(write, vocab.max_n() as i32)?;
+    write_i32(write, FASTTEXT_LR_UPDATE_RATE)?;
+    write.write_all(&FASTTEXT_SAMPLING_THRESHOLD.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_dictionary(write: &mut dyn Write, vocab: &FastTextSubwordVocab) -> Result<()> {
+    let n_words = vocab.words_len() as i32;
+
+    // Size, number of words, number of labels.
+    write_i32(write, n_words)?;
+    write_i32(write, n_words)?;
+    write_i32(write, 0)?;
+
+    // Number of tokens and pruned index size (-1: not pruned). Word
+    // counts are not stored in finalfusion files, so every word gets
+    // count 1.
+    write.write_all(&(n_words as i64).to_le_bytes())?;
+    write.write_all(&(-1i64).to_le_bytes())?;
+
+    for word in vocab.words() {
+        write.write_all(word.as_bytes())?;
+        write.write_all(&[0])?;
+        write.write_all(&1i64.to_le_bytes())?;
+        // Entry type: word.
+        write.write_all(&[0])?;
+    }
+
+    Ok(())
+}
+
+/// Write the input matrix.
+///
+/// fastText word embeddings are the average of the word vector and its
+/// subword vectors. finalfusion stores these averages l2-normalized, so
+/// the original word vectors are recovered by unnormalizing the averages
+/// and subtracting the subword vectors.
+fn write_input_matrix(
+    write: &mut dyn Write,
+    embeddings: &Embeddings<VocabWrap, StorageWrap>,
+    vocab: &FastTextSubwordVocab,
+) -> Result<()> {
+    let storage = embeddings.storage();
+    let (n_rows, dims) = storage.shape();
+
+    // Quantization of the input matrix: none.
+    write.write_all(&[0])?;
+    write.write_all(&(n_rows as i64).to_le_bytes())?;
+    write.write_all(&(dims as i64).to_le_bytes())?;
+
+    for (idx, word) in vocab.words().iter().enumerate() {
+        let subword_indices = vocab.subword_indices(word).unwrap_or_default();
+        let norm = embeddings.norms().map(|norms| norms[idx]).unwrap_or(1.);
+
+        let mut embedding: Array1<f32> = storage.embedding(idx).into_owned();
+        embedding *= norm * (1 + subword_indices.len()) as f32;
+        for subword_idx in subword_indices {
+            embedding -= &storage.embedding(subword_idx).view();
+        }
+
+        write_f32s(write, embedding.iter())?;
+    }
+
+    for idx in vocab.words_len()..n_rows {
+        write_f32s(write, storage.embedding(idx).iter())?;
+    }
+
+    Ok(())
+}
+
+/// Write the output matrix.
+///
+/// The output matrix is not stored in finalfusion files, but fastText
+/// requires it to be present.
+fn write_output_matrix(write: &mut dyn Write, n_words: usize, dims: usize) -> Result<()> {
+    // Quantization of the output matrix: none.
+    write.write_all(&[0])?;
+    write.write_all(&(n_words as i64).to_le_bytes())?;
+    write.write_all(&(dims as i64).to_le_bytes())?;
+
+    let zeros = vec![0u8; dims * std::mem::size_of::<f32>()];
+    for _ in 0..n_words {
+        write.write_all(&zeros)?;
+    }
+
+    Ok(())
+}
+
+fn write_i32(write: &mut dyn Write, v: i32) -> Result<()> {
+    write.write_all(&v.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_f32s<'a>(write: &mut dyn Write, values: impl Iterator<Item = &'a f32>) -> Result<()> {
+    for v in values {
+        write.write_all(&v.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor, Seek, SeekFrom};
+
+    use finalfusion::compat::fasttext::{FastTextIndexer, ReadFastText};
+    use finalfusion::io::WriteEmbeddings;
+    use finalfusion::norms::NdNorms;
+    use finalfusion::prelude::*;
+    use finalfusion::storage::{NdArray, Storage};
+    use finalfusion::subword::BucketIndexer;
+    use finalfusion::vocab::{FastTextSubwordVocab, Vocab};
+    use ndarray::{Array1, Array2};
+
+    use super::WriteFastTextModel;
+
+    const N_BUCKETS: usize = 16;
+    const DIMS: usize = 4;
+
+    fn embeddings() -> Embeddings<VocabWrap, StorageWrap> {
+        let words = vec!["foo".to_owned(), "bar".to_owned(), "baz".to_owned()];
+        let vocab = FastTextSubwordVocab::new(words, 3, 4, FastTextIndexer::new(N_BUCKETS));
+
+        let n_rows = vocab.words_len() + N_BUCKETS;
+        let mut matrix = Array2::from_shape_fn((n_rows, DIMS), |(row, col)| {
+            ((row * DIMS + col) as f32 * 0.37).sin()
+        });
+        for mut embedding in matrix.outer_iter_mut().take(vocab.words_len()) {
+            let norm = embedding.dot(&embedding).sqrt();
+            embedding /= norm;
+        }
+        let norms = Array1::from(vec![0.5, 1.5, 3.]);
+
+        Embeddings::new(None, vocab, NdArray::from(matrix), NdNorms::new(norms)).into()
+    }
+
+    #[test]
+    fn finalfusion_fasttext_finalfusion_round_trip() {
+        let check = embeddings();
+
+        let mut finalfusion = Cursor::new(Vec::new());
+        check.write_embeddings(&mut finalfusion).unwrap();
+        finalfusion.seek(SeekFrom::Start(0)).unwrap();
+        let embeddings: Embeddings<VocabWrap, StorageWrap> =
+            Embeddings::read_embeddings(&mut finalfusion).unwrap();
+
+        let mut fasttext = Vec::new();
+        embeddings.write_fasttext_model(&mut fasttext).unwrap();
+        let embeddings = Embeddings::read_fasttext(&mut BufReader::new(&fasttext[..])).unwrap();
+
+        assert_eq!(embeddings.vocab().words(), check.vocab().words());
+        let (n_rows, dims) = check.storage().shape();
+        assert_eq!(embeddings.storage().shape(), (n_rows, dims));
+        for idx in 0..n_rows {
+            let diff = &embeddings.storage().embedding(idx) - &check.storage().embedding(idx);
+            assert!(diff.iter().all(|d| d.abs() < 1e-5));
+        }
+        let norms_diff = &embeddings.norms().unwrap().view() - &check.norms().unwrap().view();
+        assert!(norms_diff.iter().all(|d| d.abs() < 1e-5));
+    }
+
+    #[test]
+    fn writes_zero_output_matrix() {
+        let embeddings = embeddings();
+        let mut fasttext = Vec::new();
+        embeddings.write_fasttext_model(&mut fasttext).unwrap();
+
+        // Quantization flag, rows, columns, followed by the matrix.
+        let n_words = embeddings.vocab().words_len();
+        let matrix_len = n_words * DIMS * std::mem::size_of::<f32>();
+        let output = &fasttext[fasttext.len() - matrix_len - 17..];
+        assert_eq!(output[0], 0);
+        assert_eq!(output[1..9], (n_words as i64).to_le_bytes());
+        assert_eq!(output[9..17], (DIMS as i64).to_le_bytes());
+        assert!(output[17..].iter().all(|&b| b == 0));
+
+        // The output matrix directly follows the input matrix.
+        let n_rows = embeddings.storage().shape().0;
+        let input_len = n_rows * DIMS * std::mem::size_of::<f32>();
+        let input = &fasttext[fasttext.len() - matrix_len - 17 - input_len - 17..];
+        assert_eq!(input[1..9], (n_rows as i64).to_le_bytes());
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::Write;
+
+use anyhow::{bail, Result};
+use finalfusion::compat::floret::WriteFloretText;
+use finalfusion::norms::NdNorms;
+use finalfusion::prelude::*;
+use finalfusion::storage::{NdArray, Storage};
+use finalfusion::vocab::Vocab;
+use ndarray::{Array1, Array2};
+
+/// Write embeddings in the floret text format.
+///
+/// The floret writer of finalfusion is only implemented for array
+/// storage, so the embeddings are copied into an array first.
+pub fn write_floret(
+    embeddings: &Embeddings<VocabWrap, StorageWrap>,
+    write: &mut dyn Write,
+) -> Result<()> {
+    let vocab = match embeddings.vocab() {
+        VocabWrap::FloretSubwordVocab(vocab) => vocab.clone(),
+        _ => bail!(
+            "Cannot write embeddings in floret format, \
+             only vocabularies with floret hashing are supported"
+        ),
+    };
+
+    let storage = embeddings.storage();
+    let mut array = Array2::zeros(storage.shape());
+    for (idx, mut row) in array.outer_iter_mut().enumerate() {
+        row.assign(&storage.embedding(idx));
+    }
+
+    // Norms are not stored in the floret format.
+    let norms = NdNorms::new(Array1::ones(vocab.words_len()));
+
+    Embeddings::new(None, vocab, NdArray::from(array), norms).write_floret_text(write)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor, Seek, SeekFrom};
+
+    use finalfusion::compat::floret::ReadFloretText;
+    use finalfusion::io::WriteEmbeddings;
+    use finalfusion::prelude::*;
+
+    use super::write_floret;
+
+    const FLORET: &str = "4 3 3 4 2 2166136261 < >
+0 0.5 -1.25 2
+1 -0.125 0 3.75
+2 1 1e-5 -0.0625
+3 0.25 -2 0.1
+";
+
+    fn header_and_rows(text: &str) -> (Vec<String>, Vec<(String, Vec<f32>)>) {
+        let mut lines = text.lines();
+        let header = lines
+            .next()
+            .unwrap()
+            .split_whitespace()
+            .map(ToOwned::to_owned)
+            .collect();
+        let rows = lines
+            .map(|line| {
+                let mut parts = line.split_whitespace();
+                let idx = parts.next().unwrap().to_owned();
+                (idx, parts.map(|v| v.parse().unwrap()).collect())
+            })
+            .collect();
+        (header, rows)
+    }
+
+    #[test]
+    fn floret_finalfusion_floret_round_trip() {
+        let embeddings: Embeddings<VocabWrap, StorageWrap> =
+            Embeddings::read_floret_text(&mut BufReader::new(FLORET.as_bytes()))
+                .unwrap()
+                .into();
+
+        let mut finalfusion = Cursor::new(Vec::new());
+        embeddings.write_embeddings(&mut finalfusion).unwrap();
+        finalfusion.seek(SeekFrom::Start(0)).unwrap();
+        let embeddings: Embeddings<VocabWrap, StorageWrap> =
+            Embeddings::read_embeddings(&mut finalfusion).unwrap();
+
+        let mut floret = Vec::new();
+        write_floret(&embeddings, &mut floret).unwrap();
+
+        let (header, rows) = header_and_rows(FLORET);
+        let (check_header, check_rows) = header_and_rows(&String::from_utf8(floret).unwrap());
+        assert_eq!(check_header, header);
+        assert_eq!(check_rows, rows);
+    }
+}
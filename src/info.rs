@@ -0,0 +1,338 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+
+use anyhow::{ensure, Context, Result};
+use clap::{App, Arg, ArgMatches};
+use finalfusion::prelude::*;
+use finalfusion::storage::Storage;
+use finalfusion::subword::{BucketIndexer, Indexer};
+use finalfusion::vocab::Vocab;
+use serde::Serialize;
+use toml::Value;
+
+use crate::io::{check_output, AtomicOutput, Overwrite, STDIO_FILENAME};
+use crate::report::ReportFormat;
+use crate::FinalfusionApp;
+
+// Argument constants
+static INPUT: &str = "INPUT";
+static OUTPUT: &str = "OUTPUT";
+
+const FINALFUSION_MAGIC: &[u8] = b"FiFu";
+
+pub struct InfoApp {
+    input_filename: String,
+    output_filename: Option<String>,
+    output_format: ReportFormat,
+    overwrite: Overwrite,
+}
+
+impl FinalfusionApp for InfoApp {
+    fn app() -> App<'static, 'static> {
+        App::new("info")
+            .about("Summarize the structure of finalfusion embeddings")
+            .arg(
+                Arg::with_name(INPUT)
+                    .help("finalfusion model")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(Arg::with_name(OUTPUT).help("Output file").index(2))
+            .arg(ReportFormat::new_clap_arg())
+            .arg(Overwrite::new_clap_arg())
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self> {
+        let input_filename = matches.value_of(INPUT).unwrap().to_owned();
+        let output_filename = matches.value_of(OUTPUT).map(ToOwned::to_owned);
+        let output_format = ReportFormat::parse_clap_matches(matches)?;
+
+        Ok(InfoApp {
+            input_filename,
+            output_filename,
+            output_format,
+            overwrite: Overwrite::parse_clap_matches(matches),
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let output_filename = self.output_filename.as_deref().unwrap_or(STDIO_FILENAME);
+        check_output(&self.input_filename, output_filename, self.overwrite)?;
+
+        let info = read_info(&self.input_filename)?;
+
+        let mut writer = BufWriter::new(AtomicOutput::create(output_filename, self.overwrite)?);
+
+        match self.output_format {
+            ReportFormat::Json => {
+                serde_json::to_writer_pretty(&mut writer, &info)
+                    .context("Cannot write embeddings info")?;
+                writeln!(writer)?;
+            }
+            ReportFormat::Text => {
+                write!(writer, "{}", info).context("Cannot write embeddings info")?
+            }
+        }
+
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("Cannot write embeddings info")?
+            .finish()
+    }
+}
+
+#[derive(Serialize)]
+struct Info {
+    chunks: Vec<ChunkInfo>,
+    vocab: VocabInfo,
+    storage: StorageInfo,
+    norms: bool,
+    metadata: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct ChunkInfo {
+    chunk: String,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct VocabInfo {
+    #[serde(rename = "type")]
+    vocab_type: &'static str,
+    words: usize,
+    subwords: Option<usize>,
+    min_n: Option<u32>,
+    max_n: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct StorageInfo {
+    #[serde(rename = "type")]
+    storage_type: &'static str,
+    rows: usize,
+    dims: usize,
+    quantizer: Option<QuantizerInfo>,
+}
+
+#[derive(Serialize)]
+struct QuantizerInfo {
+    subquantizers: u32,
+    bits: u32,
+    projection: bool,
+    reconstruction_norms: bool,
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Chunks:")?;
+        for chunk in &self.chunks {
+            writeln!(
+                f,
+                "  {:<24} offset: {:<12} size: {}",
+                chunk.chunk, chunk.offset, chunk.size
+            )?;
+        }
+
+        writeln!(f, "Vocabulary: {}", self.vocab.vocab_type)?;
+        writeln!(f, "  Words: {}", self.vocab.words)?;
+        if let Some(subwords) = self.vocab.subwords {
+            writeln!(f, "  Subwords: {}", subwords)?;
+        }
+        if let (Some(min_n), Some(max_n)) = (self.vocab.min_n, self.vocab.max_n) {
+            writeln!(f, "  N-gram range: {}-{}", min_n, max_n)?;
+        }
+
+        writeln!(f, "Storage: {}", self.storage.storage_type)?;
+        writeln!(f, "  Rows: {}", self.storage.rows)?;
+        writeln!(f, "  Dims: {}", self.storage.dims)?;
+        if let Some(quantizer) = &self.storage.quantizer {
+            writeln!(f, "  Subquantizers: {}", quantizer.subquantizers)?;
+            writeln!(f, "  Quantizer bits: {}", quantizer.bits)?;
+            writeln!(f, "  Projection: {}", yes_no(quantizer.projection))?;
+            writeln!(
+                f,
+                "  Reconstruction norms: {}",
+                yes_no(quantizer.reconstruction_norms)
+            )?;
+        }
+
+        writeln!(f, "Norms: {}", yes_no(self.norms))?;
+        writeln!(f, "Metadata: {}", yes_no(self.metadata.is_some()))
+    }
+}
+
+fn yes_no(v: bool) -> &'static str {
+    if v {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn read_info(filename: &str) -> Result<Info> {
+    let f = File::open(filename).context(format!("Cannot open embeddings file: {}", filename))?;
+    let mut reader = BufReader::new(f);
+
+    let chunks =
+        read_chunks(&mut reader).context(format!("Cannot read chunks from {}", filename))?;
+    let quantizer = match chunks.iter().find(|chunk| chunk.chunk == "QuantizedArray") {
+        Some(chunk) => Some(
+            read_quantizer_info(&mut reader, chunk)
+                .context(format!("Cannot read quantizer from {}", filename))?,
+        ),
+        None => None,
+    };
+
+    reader.seek(SeekFrom::Start(0))?;
+    let embeddings: Embeddings<VocabWrap, StorageWrap> =
+        MmapEmbeddings::mmap_embeddings(&mut reader)
+            .context(format!("Cannot read embeddings from {}", filename))?;
+
+    let (rows, dims) = embeddings.storage().shape();
+    // The embeddings are memory-mapped, so the storage type is derived
+    // from the chunk that is stored in the file.
+    let storage_type = chunks
+        .iter()
+        .find_map(|chunk| match chunk.chunk.as_str() {
+            "NdArray" => Some("array"),
+            "QuantizedArray" => Some("quantized array"),
+            _ => None,
+        })
+        .context(format!("No storage chunk in {}", filename))?;
+
+    Ok(Info {
+        chunks,
+        vocab: vocab_info(embeddings.vocab()),
+        storage: StorageInfo {
+            storage_type,
+            rows,
+            dims,
+            quantizer,
+        },
+        norms: embeddings.norms().is_some(),
+        metadata: embeddings.metadata().map(|metadata| (**metadata).clone()),
+    })
+}
+
+fn vocab_info(vocab: &VocabWrap) -> VocabInfo {
+    let (vocab_type, min_n, max_n) = match vocab {
+        VocabWrap::SimpleVocab(_) => ("simple", None, None),
+        VocabWrap::BucketSubwordVocab(vocab) => {
+            ("bucket subwords", Some(vocab.min_n()), Some(vocab.max_n()))
+        }
+        VocabWrap::ExplicitSubwordVocab(vocab) => {
+            ("explicit n-grams", Some(vocab.min_n()), Some(vocab.max_n()))
+        }
+        VocabWrap::FastTextSubwordVocab(vocab) => (
+            "fastText subwords",
+            Some(vocab.min_n()),
+            Some(vocab.max_n()),
+        ),
+        VocabWrap::FloretSubwordVocab(vocab) => {
+            ("floret subwords", Some(vocab.min_n()), Some(vocab.max_n()))
+        }
+    };
+
+    let subwords = match vocab {
+        VocabWrap::SimpleVocab(_) => None,
+        VocabWrap::BucketSubwordVocab(vocab) => Some(vocab.indexer().buckets()),
+        VocabWrap::FastTextSubwordVocab(vocab) => Some(vocab.indexer().buckets()),
+        VocabWrap::FloretSubwordVocab(vocab) => Some(vocab.indexer().upper_bound() as usize),
+        VocabWrap::ExplicitSubwordVocab(vocab) => Some(vocab.vocab_len() - vocab.words_len()),
+    };
+
+    VocabInfo {
+        vocab_type,
+        words: vocab.words_len(),
+        subwords,
+        min_n,
+        max_n,
+    }
+}
+
+/// Read the chunk identifiers, offsets and sizes.
+fn read_chunks<R>(reader: &mut R) -> Result<Vec<ChunkInfo>>
+where
+    R: Read + Seek,
+{
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    ensure!(
+        magic == FINALFUSION_MAGIC,
+        "File is not in finalfusion format"
+    );
+
+    let _version = read_u32(reader)?;
+    let n_chunks = read_u32(reader)?;
+    for _ in 0..n_chunks {
+        read_u32(reader)?;
+    }
+
+    let mut chunks = Vec::with_capacity(n_chunks as usize);
+    for _ in 0..n_chunks {
+        let offset = reader.stream_position()?;
+        let identifier = read_u32(reader)?;
+        let size = read_u64(reader)?;
+        chunks.push(ChunkInfo {
+            chunk: chunk_name(identifier),
+            offset,
+            size,
+        });
+        reader.seek(SeekFrom::Current(size as i64))?;
+    }
+
+    Ok(chunks)
+}
+
+/// Read quantizer parameters from the header of a quantized array chunk.
+fn read_quantizer_info<R>(reader: &mut R, chunk: &ChunkInfo) -> Result<QuantizerInfo>
+where
+    R: Read + Seek,
+{
+    // Skip the chunk identifier and chunk size.
+    reader.seek(SeekFrom::Start(chunk.offset + 12))?;
+
+    let projection = read_u32(reader)? != 0;
+    let reconstruction_norms = read_u32(reader)? != 0;
+    let subquantizers = read_u32(reader)?;
+    let _reconstructed_len = read_u32(reader)?;
+    let n_centroids = read_u32(reader)?;
+
+    Ok(QuantizerInfo {
+        subquantizers,
+        bits: n_centroids.trailing_zeros(),
+        projection,
+        reconstruction_norms,
+    })
+}
+
+fn chunk_name(identifier: u32) -> String {
+    match identifier {
+        1 => "SimpleVocab".to_string(),
+        2 => "NdArray".to_string(),
+        3 => "BucketSubwordVocab".to_string(),
+        4 => "QuantizedArray".to_string(),
+        5 => "Metadata".to_string(),
+        6 => "NdNorms".to_string(),
+        7 => "FastTextSubwordVocab".to_string(),
+        8 => "ExplicitSubwordVocab".to_string(),
+        9 => "FloretSubwordVocab".to_string(),
+        unknown => format!("Unknown({})", unknown),
+    }
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
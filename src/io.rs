@@ -1,9 +1,13 @@
 use std::convert::TryFrom;
 use std::fmt;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Stdout, Write};
+use std::path::Path;
+use std::str;
 
-use anyhow::{anyhow, bail, Context, Error, Result};
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use clap::{Arg, ArgMatches};
+use tempfile::NamedTempFile;
 
 use finalfusion::compat::floret::ReadFloretText;
 use finalfusion::compat::text::{WriteText, WriteTextDims};
@@ -11,8 +15,13 @@ use finalfusion::compat::word2vec::WriteWord2Vec;
 use finalfusion::io::WriteEmbeddings;
 use finalfusion::prelude::*;
 
+use crate::compression::{CompressedWriter, Compression};
+use crate::fasttext::WriteFastTextModel;
+use crate::floret::write_floret;
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EmbeddingFormat {
+    Auto,
     FastText,
     FinalFusion,
     FinalFusionMmap,
@@ -29,6 +38,7 @@ impl TryFrom<&str> for EmbeddingFormat {
         use self::EmbeddingFormat::*;
 
         match format {
+            "auto" => Ok(Auto),
             "fasttext" => Ok(FastText),
             "finalfusion" => Ok(FinalFusion),
             "finalfusion_mmap" => Ok(FinalFusionMmap),
@@ -45,6 +55,7 @@ impl fmt::Display for EmbeddingFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use EmbeddingFormat::*;
         let s = match self {
+            Auto => "auto",
             FastText => "fasttext",
             FinalFusion => "finalfusion",
             FinalFusionMmap => "finalfusion_mmap",
@@ -58,18 +69,32 @@ impl fmt::Display for EmbeddingFormat {
     }
 }
 
+/// Size of the read buffer, must be large enough to sniff the first records.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Filename that is used for standard input and output.
+pub const STDIO_FILENAME: &str = "-";
+
+const FORCE: &str = "force";
+
+/// Magic number at the start of fastText binary files.
+const FASTTEXT_MAGIC: u32 = 793_712_314;
+
+/// Magic at the start of finalfusion files.
+const FINALFUSION_MAGIC: &[u8] = b"FiFu";
+
 pub fn read_embeddings(
     filename: &str,
     embedding_format: EmbeddingFormat,
 ) -> Result<Embeddings<VocabWrap, StorageWrap>> {
-    let f = File::open(filename).context("Cannot open embeddings file")?;
-    let mut reader = BufReader::new(f);
+    let (mut reader, embedding_format) = EmbeddingsReader::open(filename, embedding_format)?;
 
     use self::EmbeddingFormat::*;
     let embeds = match embedding_format {
+        Auto => unreachable!(),
         FastText => ReadFastText::read_fasttext(&mut reader).map(Embeddings::into),
         FinalFusion => ReadEmbeddings::read_embeddings(&mut reader),
-        FinalFusionMmap => MmapEmbeddings::mmap_embeddings(&mut reader),
+        FinalFusionMmap => MmapEmbeddings::mmap_embeddings(reader.file_mut(filename)?),
         Floret => ReadFloretText::read_floret_text(&mut reader).map(Embeddings::into),
         Word2Vec => ReadWord2Vec::read_word2vec_binary(&mut reader).map(Embeddings::into),
         Text => ReadText::read_text(&mut reader).map(Embeddings::into),
@@ -83,14 +108,14 @@ pub fn read_embeddings_view(
     filename: &str,
     embedding_format: EmbeddingFormat,
 ) -> Result<Embeddings<VocabWrap, StorageViewWrap>> {
-    let f = File::open(filename).context("Cannot open embeddings file")?;
-    let mut reader = BufReader::new(f);
+    let (mut reader, embedding_format) = EmbeddingsReader::open(filename, embedding_format)?;
 
     use self::EmbeddingFormat::*;
     let embeds = match embedding_format {
+        Auto => unreachable!(),
         FastText => ReadFastText::read_fasttext(&mut reader).map(Embeddings::into),
         FinalFusion => ReadEmbeddings::read_embeddings(&mut reader),
-        FinalFusionMmap => MmapEmbeddings::mmap_embeddings(&mut reader),
+        FinalFusionMmap => MmapEmbeddings::mmap_embeddings(reader.file_mut(filename)?),
         Floret => ReadFloretText::read_floret_text(&mut reader).map(Embeddings::into),
         Word2Vec => ReadWord2Vec::read_word2vec_binary(&mut reader).map(Embeddings::into),
         Text => ReadText::read_text(&mut reader).map(Embeddings::into),
@@ -100,26 +125,654 @@ pub fn read_embeddings_view(
     Ok(embeds?)
 }
 
+/// Reader for (possibly compressed) embeddings.
+pub enum EmbeddingsReader {
+    /// Uncompressed file.
+    File(BufReader<File>),
+
+    /// Data that was read into memory.
+    ///
+    /// Reading finalfusion embeddings requires seeking, so finalfusion
+    /// embeddings from a stream are read into memory.
+    Memory(Cursor<Vec<u8>>),
+
+    /// Standard input or decompressed stream.
+    Stream(BufReader<Box<dyn Read>>),
+}
+
+impl EmbeddingsReader {
+    /// Open embeddings for reading.
+    ///
+    /// Embeddings are read from standard input when the filename is `-`.
+    /// Compression is detected from the magic of the data. Returns the
+    /// reader and the embedding format, with the `auto` format resolved.
+    pub fn open(
+        filename: &str,
+        embedding_format: EmbeddingFormat,
+    ) -> Result<(Self, EmbeddingFormat)> {
+        let mut reader = if filename == STDIO_FILENAME {
+            ensure!(
+                embedding_format != EmbeddingFormat::FinalFusionMmap,
+                "Cannot memory map embeddings from standard input, use the finalfusion format"
+            );
+            EmbeddingsReader::Stream(BufReader::with_capacity(
+                READ_BUFFER_SIZE,
+                Box::new(io::stdin()),
+            ))
+        } else {
+            let f = File::open(filename)
+                .context(format!("Cannot open embeddings file: {}", filename))?;
+            EmbeddingsReader::File(BufReader::with_capacity(READ_BUFFER_SIZE, f))
+        };
+
+        let compression = Compression::from_magic(
+            reader
+                .fill_buf()
+                .context(format!("Cannot read embeddings from {}", filename))?,
+        );
+        if compression != Compression::None {
+            eprintln!(
+                "Reading {} compressed embeddings from {}",
+                compression, filename
+            );
+            reader = EmbeddingsReader::Stream(BufReader::with_capacity(
+                READ_BUFFER_SIZE,
+                compression.decompress(reader)?,
+            ));
+        }
+
+        let embedding_format = resolve_format(&mut reader, filename, embedding_format)?;
+        if embedding_format == EmbeddingFormat::FinalFusion {
+            reader = reader
+                .into_memory()
+                .context(format!("Cannot read embeddings from {}", filename))?;
+        }
+
+        Ok((reader, embedding_format))
+    }
+
+    /// Get the underlying file reader, used for memory mapping.
+    pub fn file_mut(&mut self, filename: &str) -> Result<&mut BufReader<File>> {
+        match self {
+            EmbeddingsReader::File(reader) => Ok(reader),
+            _ => bail!(
+                "Cannot memory map {}, only uncompressed files can be memory mapped",
+                filename
+            ),
+        }
+    }
+
+    fn into_memory(self) -> io::Result<Self> {
+        match self {
+            EmbeddingsReader::Stream(mut reader) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                Ok(EmbeddingsReader::Memory(Cursor::new(data)))
+            }
+            reader => Ok(reader),
+        }
+    }
+}
+
+impl Read for EmbeddingsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            EmbeddingsReader::File(reader) => reader.read(buf),
+            EmbeddingsReader::Memory(reader) => reader.read(buf),
+            EmbeddingsReader::Stream(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl BufRead for EmbeddingsReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            EmbeddingsReader::File(reader) => reader.fill_buf(),
+            EmbeddingsReader::Memory(reader) => reader.fill_buf(),
+            EmbeddingsReader::Stream(reader) => reader.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            EmbeddingsReader::File(reader) => reader.consume(amt),
+            EmbeddingsReader::Memory(reader) => reader.consume(amt),
+            EmbeddingsReader::Stream(reader) => reader.consume(amt),
+        }
+    }
+}
+
+impl Seek for EmbeddingsReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            EmbeddingsReader::File(reader) => reader.seek(pos),
+            EmbeddingsReader::Memory(reader) => reader.seek(pos),
+            EmbeddingsReader::Stream(_) => Err(io::Error::other("Cannot seek in a stream")),
+        }
+    }
+}
+
+/// Resolve the `auto` format by sniffing the start of the embeddings.
+///
+/// Other formats are returned as-is. The reader is not advanced.
+fn resolve_format(
+    reader: &mut impl BufRead,
+    filename: &str,
+    embedding_format: EmbeddingFormat,
+) -> Result<EmbeddingFormat> {
+    if embedding_format != EmbeddingFormat::Auto {
+        return Ok(embedding_format);
+    }
+
+    let embedding_format =
+        detect_format(reader).context(format!("Cannot detect embedding format of {}", filename))?;
+    eprintln!("Detected {} embeddings in {}", embedding_format, filename);
+
+    Ok(embedding_format)
+}
+
+fn detect_format(reader: &mut impl BufRead) -> Result<EmbeddingFormat> {
+    use self::EmbeddingFormat::*;
+
+    let data = reader.fill_buf().context("Cannot read embeddings")?;
+
+    if data.starts_with(FINALFUSION_MAGIC) {
+        return Ok(FinalFusion);
+    }
+
+    if data.len() >= 4 && u32::from_le_bytes([data[0], data[1], data[2], data[3]]) == FASTTEXT_MAGIC
+    {
+        return Ok(FastText);
+    }
+
+    let (header, rest) = split_line(data);
+    let header = str::from_utf8(header)
+        .map_err(|_| anyhow!("Unknown binary format, specify the format explicitly"))?;
+    let fields = header.split_whitespace().collect::<Vec<_>>();
+
+    // floret: buckets dims min_n max_n n_hashes seed bow eow
+    if fields.len() == 8 && fields[..6].iter().all(|field| field.parse::<u64>().is_ok()) {
+        return Ok(Floret);
+    }
+
+    // word2vec and textdims: n_words dims
+    if fields.len() == 2 && fields.iter().all(|field| field.parse::<usize>().is_ok()) {
+        let dims = fields[1].parse().unwrap();
+        return if is_text_record(rest, dims) {
+            Ok(TextDims)
+        } else {
+            Ok(Word2Vec)
+        };
+    }
+
+    // text: word v1 v2 ...
+    if fields.len() > 1 && fields[1..].iter().all(|field| field.parse::<f32>().is_ok()) {
+        return Ok(Text);
+    }
+
+    bail!("Unknown format, specify the format explicitly")
+}
+
+/// Check whether the data starts with a text record with `dims` components.
+fn is_text_record(data: &[u8], dims: usize) -> bool {
+    let complete = data.contains(&b'\n');
+    let (record, _) = split_line(data);
+
+    let record = match str::from_utf8(record) {
+        Ok(record) => record,
+        // A truncated record can end in a partial UTF-8 sequence.
+        Err(err) if !complete => str::from_utf8(&record[..err.valid_up_to()]).unwrap(),
+        Err(_) => return false,
+    };
+
+    let fields = record.split_whitespace().collect::<Vec<_>>();
+    if complete {
+        fields.len() == dims + 1 && fields[1..].iter().all(|field| field.parse::<f32>().is_ok())
+    } else {
+        // The last field of a truncated record can be partial.
+        fields.len() > 2
+            && fields[1..fields.len() - 1]
+                .iter()
+                .all(|field| field.parse::<f32>().is_ok())
+    }
+}
+
+fn split_line(data: &[u8]) -> (&[u8], &[u8]) {
+    match data.iter().position(|&b| b == b'\n') {
+        Some(idx) => (&data[..idx], &data[idx + 1..]),
+        None => (data, &[][..]),
+    }
+}
+
 pub fn write_embeddings(
     embeddings: &Embeddings<VocabWrap, StorageWrap>,
     filename: &str,
     format: EmbeddingFormat,
     unnormalize: bool,
+    overwrite: Overwrite,
 ) -> Result<()> {
-    let f =
-        File::create(filename).context(format!("Cannot create embeddings file: {}", filename))?;
-    let mut writer = BufWriter::new(f);
+    let mut writer = EmbeddingsWriter::create(filename, overwrite)?;
 
     use self::EmbeddingFormat::*;
     match format {
-        FastText => bail!("Writing to the fastText format is not supported"),
-        FinalFusion => embeddings.write_embeddings(&mut writer)?,
+        Auto => bail!("The output format must be specified explicitly"),
+        FastText => embeddings.write_fasttext_model(&mut writer)?,
+        FinalFusion => embeddings.write_embeddings(&mut PositionWriter::new(&mut writer))?,
         FinalFusionMmap => bail!("Writing to memory-mapped finalfusion file is not supported"),
-        Floret => bail!("Writing to the floret format is not supported"),
+        Floret => write_floret(embeddings, &mut writer)?,
         Word2Vec => embeddings.write_word2vec_binary(&mut writer, unnormalize)?,
         Text => embeddings.write_text(&mut writer, unnormalize)?,
         TextDims => embeddings.write_text_dims(&mut writer, unnormalize)?,
     };
 
+    writer.finish()
+}
+
+/// Writer for (possibly compressed) embeddings.
+pub struct EmbeddingsWriter {
+    filename: String,
+    inner: CompressedWriter<BufWriter<AtomicOutput>>,
+}
+
+impl EmbeddingsWriter {
+    /// Create a writer for embeddings.
+    ///
+    /// Embeddings are written to standard output when the filename is `-`.
+    /// Compression is chosen based on the extension of the filename.
+    pub fn create(filename: &str, overwrite: Overwrite) -> Result<Self> {
+        let output = AtomicOutput::create(filename, overwrite)?;
+        let compression = Compression::from_extension(filename);
+        let inner = CompressedWriter::new(BufWriter::new(output), compression)
+            .context(format!("Cannot create {} compressed stream", compression))?;
+
+        Ok(EmbeddingsWriter {
+            filename: filename.to_owned(),
+            inner,
+        })
+    }
+
+    /// Finish writing the embeddings.
+    ///
+    /// The embeddings are only moved to their final location after they
+    /// are written completely.
+    pub fn finish(self) -> Result<()> {
+        self.inner
+            .finish()
+            .and_then(|writer| writer.into_inner().map_err(|err| err.into_error()))
+            .context(format!(
+                "Cannot finish writing embeddings to {}",
+                self.filename
+            ))?
+            .finish()
+    }
+}
+
+impl Write for EmbeddingsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Policy for existing output files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overwrite {
+    Allow,
+    Deny,
+}
+
+impl Overwrite {
+    pub fn new_clap_arg() -> Arg<'static, 'static> {
+        Arg::with_name(FORCE)
+            .long("force")
+            .help("Overwrite existing output files")
+    }
+
+    pub fn parse_clap_matches(matches: &ArgMatches) -> Self {
+        if matches.is_present(FORCE) {
+            Overwrite::Allow
+        } else {
+            Overwrite::Deny
+        }
+    }
+}
+
+/// Check that an output file can be written.
+///
+/// Fails when the output is the same file as the input, or when the output
+/// exists and may not be overwritten.
+pub fn check_output(
+    input_filename: &str,
+    output_filename: &str,
+    overwrite: Overwrite,
+) -> Result<()> {
+    if output_filename == STDIO_FILENAME || !Path::new(output_filename).exists() {
+        return Ok(());
+    }
+
+    ensure!(
+        input_filename == STDIO_FILENAME
+            || !same_file::is_same_file(input_filename, output_filename).unwrap_or(false),
+        "Input and output are the same file: {}",
+        output_filename
+    );
+
+    ensure!(
+        overwrite == Overwrite::Allow,
+        "Output file {} already exists, use --force to overwrite it",
+        output_filename
+    );
+
+    Ok(())
+}
+
+/// Output that is replaced atomically.
+///
+/// Data is written to a temporary file in the same directory as the output
+/// file. The temporary file is renamed to the output file when the output
+/// is finished, so that an interrupted write never truncates an existing
+/// file. Standard output is used when the filename is `-`.
+pub enum AtomicOutput {
+    File {
+        filename: String,
+        overwrite: Overwrite,
+        tmp: NamedTempFile,
+    },
+    Stdout(Stdout),
+}
+
+impl AtomicOutput {
+    /// Create an output.
+    ///
+    /// Use `check_output` to check that the output may be written before
+    /// doing any work. With `Overwrite::Deny`, `finish` fails rather than
+    /// replacing a file that was created in the meantime.
+    pub fn create(filename: &str, overwrite: Overwrite) -> Result<Self> {
+        if filename == STDIO_FILENAME {
+            return Ok(AtomicOutput::Stdout(io::stdout()));
+        }
+
+        let dir = match Path::new(filename).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let tmp = tempfile::Builder::new()
+            .prefix(".finalfusion")
+            .tempfile_in(dir)
+            .context(format!(
+                "Cannot create temporary file in {}",
+                dir.to_string_lossy()
+            ))?;
+
+        Ok(AtomicOutput::File {
+            filename: filename.to_owned(),
+            overwrite,
+            tmp,
+        })
+    }
+
+    /// Finish the output, moving the file into place.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            AtomicOutput::File {
+                filename,
+                overwrite,
+                tmp,
+            } => {
+                tmp.as_file()
+                    .sync_all()
+                    .context(format!("Cannot sync data for {}", filename))?;
+                set_output_permissions(tmp.as_file(), &filename)
+                    .context(format!("Cannot set permissions of {}", filename))?;
+
+                let persisted = match overwrite {
+                    Overwrite::Allow => tmp.persist(&filename),
+                    Overwrite::Deny => tmp.persist_noclobber(&filename),
+                };
+                persisted
+                    .map_err(|err| err.error)
+                    .context(format!("Cannot move temporary file to {}", filename))?;
+            }
+            AtomicOutput::Stdout(mut stdout) => {
+                stdout.flush().context("Cannot flush standard output")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for AtomicOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AtomicOutput::File { tmp, .. } => tmp.write(buf),
+            AtomicOutput::Stdout(stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            AtomicOutput::File { tmp, .. } => tmp.flush(),
+            AtomicOutput::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+/// Temporary files are only readable by the owner. Give the output the
+/// permissions of the file that it replaces, or the permissions of a
+/// newly created file under the current umask.
+#[cfg(unix)]
+fn set_output_permissions(file: &File, filename: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let permissions = match fs::metadata(filename) {
+        Ok(metadata) => metadata.permissions(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            fs::Permissions::from_mode(0o666 & !umask())
+        }
+        Err(err) => return Err(err),
+    };
+
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_output_permissions(_file: &File, _filename: &str) -> io::Result<()> {
     Ok(())
 }
+
+/// Get the umask of the process.
+#[cfg(unix)]
+#[allow(clippy::useless_conversion)]
+fn umask() -> u32 {
+    // The umask can only be read by replacing it, restore it right away.
+    let mask = unsafe {
+        let mask = libc::umask(0);
+        libc::umask(mask);
+        mask
+    };
+
+    // mode_t is not u32 on all platforms.
+    mask.into()
+}
+
+/// Writer that keeps track of its position.
+///
+/// finalfusion chunks are padded based on the stream position. This writer
+/// provides the position for streams that do not support seeking, such as
+/// compressed streams and standard output. No other seeks are supported.
+pub struct PositionWriter<W> {
+    inner: W,
+    position: u64,
+}
+
+impl<W> PositionWriter<W>
+where
+    W: Write,
+{
+    pub fn new(inner: W) -> Self {
+        PositionWriter { inner, position: 0 }
+    }
+}
+
+impl<W> Write for PositionWriter<W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n_written = self.inner.write(buf)?;
+        self.position += n_written as u64;
+        Ok(n_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W> Seek for PositionWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(io::Error::other("Only the current position can be queried")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::{
+        detect_format, is_text_record, AtomicOutput, EmbeddingFormat, Overwrite, FASTTEXT_MAGIC,
+    };
+
+    fn detect(mut data: &[u8]) -> EmbeddingFormat {
+        detect_format(&mut data).unwrap()
+    }
+
+    #[test]
+    fn detects_binary_formats() {
+        assert_eq!(
+            detect(b"FiFu\x00\x00\x00\x00"),
+            EmbeddingFormat::FinalFusion
+        );
+        assert_eq!(
+            detect(&FASTTEXT_MAGIC.to_le_bytes()),
+            EmbeddingFormat::FastText
+        );
+    }
+
+    #[test]
+    fn detects_floret_header() {
+        assert_eq!(
+            detect(b"50000 300 4 5 2 2166136261 < >\n0 0.1 0.2\n"),
+            EmbeddingFormat::Floret
+        );
+    }
+
+    #[test]
+    fn distinguishes_textdims_from_word2vec() {
+        assert_eq!(
+            detect(b"2 3\nfoo 1 2 3\nbar 4 5 6\n"),
+            EmbeddingFormat::TextDims
+        );
+        assert_eq!(
+            detect(b"2 3\nfoo \x00\x00\x80\x3f\x00\x00\x00\x40\x00\x00\x40\x40"),
+            EmbeddingFormat::Word2Vec
+        );
+    }
+
+    #[test]
+    fn detects_text() {
+        assert_eq!(detect(b"foo 1 2 3\nbar 4 5 6\n"), EmbeddingFormat::Text);
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(detect_format(&mut &b"\xff\xfe\xfd\xfc"[..]).is_err());
+        assert!(detect_format(&mut &b"hello world\n"[..]).is_err());
+    }
+
+    #[test]
+    fn text_record_checks_dimensionality() {
+        assert!(is_text_record(b"foo 1 2 3\n", 3));
+        assert!(!is_text_record(b"foo 1 2\n", 3));
+        assert!(!is_text_record(b"foo 1 2 bar\n", 3));
+    }
+
+    #[test]
+    fn truncated_text_record() {
+        // The last component of a truncated record can be partial.
+        assert!(is_text_record(b"foo 1.5 2.2", 3));
+        // A truncated record can end in a partial UTF-8 sequence.
+        assert!(is_text_record(
+            "t\u{fc}bingen 1 2 \u{fc}"
+                .as_bytes()
+                .split_last()
+                .unwrap()
+                .1,
+            3
+        ));
+        // Invalid UTF-8 in a complete record is not text.
+        assert!(!is_text_record(b"foo\xff 1 2 3\n", 3));
+    }
+
+    fn write_output(filename: &str, overwrite: Overwrite) -> anyhow::Result<()> {
+        let mut output = AtomicOutput::create(filename, overwrite)?;
+        output.write_all(b"output\n")?;
+        output.finish()
+    }
+
+    #[test]
+    fn existing_output_is_only_replaced_when_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("out.txt");
+        let filename = filename.to_str().unwrap();
+        fs::write(filename, "existing\n").unwrap();
+
+        assert!(write_output(filename, Overwrite::Deny).is_err());
+        assert_eq!(fs::read_to_string(filename).unwrap(), "existing\n");
+
+        write_output(filename, Overwrite::Allow).unwrap();
+        assert_eq!(fs::read_to_string(filename).unwrap(), "output\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_output_has_permissions_of_new_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let reference = dir.path().join("reference.txt");
+        fs::File::create(&reference).unwrap();
+        let filename = dir.path().join("out.txt");
+        write_output(filename.to_str().unwrap(), Overwrite::Deny).unwrap();
+
+        assert_eq!(
+            fs::metadata(filename).unwrap().permissions().mode(),
+            fs::metadata(reference).unwrap().permissions().mode()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_output_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let filename = dir.path().join("out.txt");
+        fs::write(&filename, "existing\n").unwrap();
+        fs::set_permissions(&filename, fs::Permissions::from_mode(0o640)).unwrap();
+        write_output(filename.to_str().unwrap(), Overwrite::Allow).unwrap();
+
+        assert_eq!(
+            fs::metadata(filename).unwrap().permissions().mode() & 0o777,
+            0o640
+        );
+    }
+}
@@ -22,29 +22,51 @@ mod analogy;
 
 mod bucket_to_explicit;
 
+mod compression;
+
 mod compute_accuracy;
 
+mod compute_correlation;
+
 mod convert;
 
+mod fasttext;
+
+mod floret;
+
+mod info;
+
 pub mod io;
 
+mod objective;
+
 mod metadata;
 
+mod neighbors;
+
 mod quantize;
 
 mod reconstruct;
 
+mod report;
+
 mod select;
 
 mod similar;
 
 mod similarity;
 
+mod stats;
+
+mod streaming;
+
 mod traits;
 pub use self::traits::FinalfusionApp;
 
 pub mod util;
 
+mod validate;
+
 static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
     AppSettings::DontCollapseArgsInUsage,
     AppSettings::UnifiedHelpMessage,
@@ -52,17 +74,22 @@ static DEFAULT_CLAP_SETTINGS: &[AppSettings] = &[
 ];
 
 fn main() -> Result<()> {
+    env_logger::init();
+
     // Known subapplications.
     let apps = vec![
         analogy::AnalogyApp::app(),
         bucket_to_explicit::BucketToExplicitApp::app(),
         compute_accuracy::ComputeAccuracyApp::app(),
+        compute_correlation::ComputeCorrelationApp::app(),
         convert::ConvertApp::app(),
+        info::InfoApp::app(),
         metadata::MetadataApp::app(),
         quantize::QuantizeApp::app(),
         reconstruct::ReconstructApp::app(),
         select::SelectApp::app(),
         similar::SimilarApp::app(),
+        validate::ValidateApp::app(),
     ];
 
     let cli = App::new("finalfusion")
@@ -97,9 +124,14 @@ fn main() -> Result<()> {
             matches.subcommand_matches("compute-accuracy").unwrap(),
         )?
         .run(),
+        "compute-correlation" => compute_correlation::ComputeCorrelationApp::parse(
+            matches.subcommand_matches("compute-correlation").unwrap(),
+        )?
+        .run(),
         "convert" => {
             convert::ConvertApp::parse(matches.subcommand_matches("convert").unwrap())?.run()
         }
+        "info" => info::InfoApp::parse(matches.subcommand_matches("info").unwrap())?.run(),
         "metadata" => {
             metadata::MetadataApp::parse(matches.subcommand_matches("metadata").unwrap())?.run()
         }
@@ -114,6 +146,9 @@ fn main() -> Result<()> {
         "similar" => {
             similar::SimilarApp::parse(matches.subcommand_matches("similar").unwrap())?.run()
         }
+        "validate" => {
+            validate::ValidateApp::parse(matches.subcommand_matches("validate").unwrap())?.run()
+        }
         _unknown => unreachable!(),
     }
 }
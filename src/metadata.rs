@@ -5,9 +5,9 @@ use anyhow::{Context, Result};
 use clap::{App, Arg, ArgMatches};
 use finalfusion::io::ReadMetadata;
 use finalfusion::metadata::Metadata;
-use stdinout::Output;
 use toml::ser::to_string_pretty;
 
+use crate::io::{check_output, AtomicOutput, Overwrite, STDIO_FILENAME};
 use crate::FinalfusionApp;
 
 // Argument constants
@@ -17,6 +17,7 @@ static OUTPUT: &str = "OUTPUT";
 pub struct MetadataApp {
     input_filename: String,
     output_filename: Option<String>,
+    overwrite: Overwrite,
 }
 
 impl FinalfusionApp for MetadataApp {
@@ -30,6 +31,7 @@ impl FinalfusionApp for MetadataApp {
                     .required(true),
             )
             .arg(Arg::with_name(OUTPUT).help("Output file").index(2))
+            .arg(Overwrite::new_clap_arg())
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
@@ -39,14 +41,18 @@ impl FinalfusionApp for MetadataApp {
         Ok(MetadataApp {
             input_filename,
             output_filename,
+            overwrite: Overwrite::parse_clap_matches(matches),
         })
     }
 
     fn run(&self) -> Result<()> {
-        let output = Output::from(self.output_filename.as_ref());
-        let mut writer = BufWriter::new(output.write().context("Cannot open output for writing")?);
+        let output_filename = self.output_filename.as_deref().unwrap_or(STDIO_FILENAME);
+        check_output(&self.input_filename, output_filename, self.overwrite)?;
 
-        if let Some(metadata) = read_metadata(&self.input_filename)? {
+        let metadata = read_metadata(&self.input_filename)?;
+
+        let mut writer = BufWriter::new(AtomicOutput::create(output_filename, self.overwrite)?);
+        if let Some(metadata) = metadata {
             writer
                 .write_all(
                     to_string_pretty(&*metadata)
@@ -56,7 +62,11 @@ impl FinalfusionApp for MetadataApp {
                 .context("Cannot write metadata")?;
         }
 
-        Ok(())
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("Cannot write metadata")?
+            .finish()
     }
 }
 
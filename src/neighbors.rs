@@ -0,0 +1,423 @@
+use std::io::BufRead;
+
+use anyhow::{ensure, Context, Result};
+use finalfusion::prelude::*;
+use finalfusion::storage::{Storage, StorageView};
+use finalfusion::vocab::Vocab;
+use ndarray::{s, Array1, Array2, ArrayView1, ArrayView2, Axis, CowArray, Ix2};
+use rayon::prelude::*;
+use stdinout::Input;
+
+use crate::similarity::SimilarityMeasure;
+use crate::util::top_k;
+
+/// Number of word embeddings that are scored at once.
+const CHUNK_SIZE: usize = 4096;
+
+/// Number of nearest neighbors used for CSLS hubness penalties.
+const CSLS_NEIGHBORS: usize = 10;
+
+/// Storage that word embeddings can be read from in chunks.
+pub trait WordChunks: Sync {
+    /// Get the embeddings of the rows `start..end`.
+    fn word_chunk(&self, start: usize, end: usize) -> CowArray<'_, f32, Ix2>;
+
+    /// Get the embeddings of the given rows.
+    fn word_rows(&self, rows: &[usize]) -> CowArray<'_, f32, Ix2>;
+}
+
+impl WordChunks for StorageWrap {
+    fn word_chunk(&self, start: usize, end: usize) -> CowArray<'_, f32, Ix2> {
+        match self {
+            StorageWrap::NdArray(array) => array.view().slice_move(s![start..end, ..]).into(),
+            StorageWrap::MmapArray(array) => array.view().slice_move(s![start..end, ..]).into(),
+            StorageWrap::QuantizedArray(_) | StorageWrap::MmapQuantizedArray(_) => {
+                self.word_rows(&(start..end).collect::<Vec<_>>())
+            }
+        }
+    }
+
+    fn word_rows(&self, rows: &[usize]) -> CowArray<'_, f32, Ix2> {
+        match self {
+            StorageWrap::NdArray(array) => array.view().select(Axis(0), rows).into(),
+            StorageWrap::MmapArray(array) => array.view().select(Axis(0), rows).into(),
+            StorageWrap::QuantizedArray(_) | StorageWrap::MmapQuantizedArray(_) => {
+                // Quantized embeddings are reconstructed row by row.
+                let mut chunk = Array2::zeros((rows.len(), self.shape().1));
+                for (&idx, mut row) in rows.iter().zip(chunk.outer_iter_mut()) {
+                    row.assign(&self.embedding(idx));
+                }
+                chunk.into()
+            }
+        }
+    }
+}
+
+impl WordChunks for StorageViewWrap {
+    fn word_chunk(&self, start: usize, end: usize) -> CowArray<'_, f32, Ix2> {
+        self.view().slice_move(s![start..end, ..]).into()
+    }
+
+    fn word_rows(&self, rows: &[usize]) -> CowArray<'_, f32, Ix2> {
+        self.view().select(Axis(0), rows).into()
+    }
+}
+
+/// Words that are considered as query results.
+#[derive(Clone, Debug)]
+pub enum Candidates {
+    /// The first `n` words of the vocabulary.
+    Prefix(usize),
+
+    /// The words with the given indices, in increasing order.
+    Indices(Vec<usize>),
+}
+
+impl Candidates {
+    /// Construct candidates from the query options.
+    ///
+    /// The candidates are the words in the file `filename` (one word per
+    /// line) or all words. When `restrict` is set, only the first
+    /// `restrict` words of the vocabulary are retained.
+    pub fn from_options(
+        vocab: &VocabWrap,
+        restrict: Option<usize>,
+        filename: Option<&str>,
+    ) -> Result<Self> {
+        let n_words = restrict
+            .map(|n| n.min(vocab.words_len()))
+            .unwrap_or_else(|| vocab.words_len());
+
+        let filename = match filename {
+            Some(filename) => filename,
+            None => return Ok(Candidates::Prefix(n_words)),
+        };
+
+        let mut indices = read_word_indices(vocab, filename)?;
+        indices.retain(|&idx| idx < n_words);
+        indices.sort_unstable();
+        indices.dedup();
+
+        Ok(Candidates::Indices(indices))
+    }
+
+    /// The number of candidates.
+    fn n_candidates(&self) -> usize {
+        match self {
+            Candidates::Prefix(n) => *n,
+            Candidates::Indices(indices) => indices.len(),
+        }
+    }
+
+    /// Get the embeddings of the candidates `start..end`.
+    fn embeddings<'a>(
+        &self,
+        storage: &'a impl WordChunks,
+        start: usize,
+        end: usize,
+    ) -> CowArray<'a, f32, Ix2> {
+        match self {
+            Candidates::Prefix(_) => storage.word_chunk(start, end),
+            Candidates::Indices(indices) => storage.word_rows(&indices[start..end]),
+        }
+    }
+
+    /// Get the word index of a candidate.
+    fn word_idx(&self, candidate: usize) -> usize {
+        match self {
+            Candidates::Prefix(_) => candidate,
+            Candidates::Indices(indices) => indices[candidate],
+        }
+    }
+
+    /// Get the candidates of the word indices in `words`, as offsets
+    /// within the candidates `start..end`.
+    fn offsets(&self, words: &[usize], start: usize, end: usize) -> Vec<usize> {
+        match self {
+            Candidates::Prefix(_) => words
+                .iter()
+                .filter(|&&idx| idx >= start && idx < end)
+                .map(|&idx| idx - start)
+                .collect(),
+            Candidates::Indices(indices) => words
+                .iter()
+                .filter_map(|idx| indices[start..end].binary_search(idx).ok())
+                .collect(),
+        }
+    }
+}
+
+/// Read a file with one word per line and look up the word indices.
+///
+/// Words that are not in the vocabulary are reported on standard error.
+pub fn read_word_indices(vocab: &VocabWrap, filename: &str) -> Result<Vec<usize>> {
+    let input = Input::from(Some(filename));
+    let reader = input
+        .buf_read()
+        .context(format!("Cannot open word file: {}", filename))?;
+
+    let mut indices = Vec::new();
+    let mut n_unknown = 0;
+    for line in reader.lines() {
+        let line = line.context(format!("Cannot read line from {}", filename))?;
+        let word = line.trim();
+        if word.is_empty() {
+            continue;
+        }
+
+        match vocab.idx(word).and_then(|idx| idx.word()) {
+            Some(idx) => indices.push(idx),
+            None => n_unknown += 1,
+        }
+    }
+
+    if n_unknown != 0 {
+        eprintln!(
+            "{} word(s) in {} are not in the vocabulary",
+            n_unknown, filename
+        );
+    }
+
+    Ok(indices)
+}
+
+/// Queries for a nearest neighbor search.
+pub struct Queries {
+    /// The l2-normalized query vectors, one query per row.
+    pub vectors: Array2<f32>,
+
+    /// The norms of the unnormalized query vectors.
+    pub norms: Vec<f32>,
+
+    /// The indices of the words that are not returned for each query.
+    pub skips: Vec<Vec<usize>>,
+}
+
+/// Nearest neighbor search over candidate words.
+pub struct NearestNeighbors<'a> {
+    embeddings: &'a Embeddings<VocabWrap, StorageWrap>,
+    candidates: Candidates,
+    measure: SimilarityMeasure,
+
+    /// CSLS hubness penalties of the candidates, indexed by word.
+    hubness: Option<Array1<f32>>,
+}
+
+impl<'a> NearestNeighbors<'a> {
+    /// Construct a nearest neighbor search.
+    ///
+    /// For CSLS, the hubness penalties of all candidates are computed,
+    /// which requires a search for the neighbors of every candidate.
+    /// Dot products and Euclidean distances require the norms of the
+    /// unnormalized embeddings.
+    pub fn new(
+        embeddings: &'a Embeddings<VocabWrap, StorageWrap>,
+        candidates: Candidates,
+        measure: SimilarityMeasure,
+    ) -> Result<Self> {
+        ensure!(
+            !matches!(
+                measure,
+                SimilarityMeasure::Dot | SimilarityMeasure::Euclidean
+            ) || embeddings.norms().is_some(),
+            "The {} similarity cannot be used with embeddings without norms",
+            measure
+        );
+
+        let hubness = if measure == SimilarityMeasure::Csls {
+            Some(candidate_hubness(
+                embeddings.storage(),
+                &candidates,
+                embeddings.vocab().words_len(),
+            ))
+        } else {
+            None
+        };
+
+        Ok(NearestNeighbors {
+            embeddings,
+            candidates,
+            measure,
+            hubness,
+        })
+    }
+
+    /// Find the `k` most similar words for each query.
+    ///
+    /// When `min_similarity` is given, only words with at least this
+    /// similarity are returned.
+    pub fn nearest_words(
+        &self,
+        queries: &Queries,
+        k: usize,
+        min_similarity: Option<f32>,
+    ) -> Vec<Vec<(&'a str, f32)>> {
+        let storage = self.embeddings.storage();
+        let norms = self.embeddings.norms();
+
+        let query_hubness = match self.hubness {
+            Some(_) => mean_top_k_cosines(
+                storage,
+                &self.candidates,
+                queries.vectors.view(),
+                &queries.skips,
+            ),
+            None => vec![0.; queries.skips.len()],
+        };
+
+        let vocab = self.embeddings.vocab();
+        top_k_words(
+            storage,
+            &self.candidates,
+            queries.vectors.view(),
+            &queries.skips,
+            k,
+            |query_idx, dots, words| {
+                let mut scores = dots.column(query_idx).to_owned();
+                for (score, &word) in scores.iter_mut().zip(words) {
+                    // Norms are only missing for measures that do not use them.
+                    let word_norm = norms.map(|norms| norms[word]).unwrap_or(1.);
+                    let word_hubness = self.hubness.as_ref().map(|h| h[word]).unwrap_or(0.);
+                    *score = self.measure.combine_parts(
+                        *score,
+                        (queries.norms[query_idx], word_norm),
+                        (query_hubness[query_idx], word_hubness),
+                    );
+
+                    // Words with a NaN score are not returned.
+                    if min_similarity.map(|min| *score < min).unwrap_or(false) {
+                        *score = f32::NAN;
+                    }
+                }
+                scores
+            },
+        )
+        .into_iter()
+        .map(|results| {
+            results
+                .into_iter()
+                .map(|(idx, score)| (vocab.words()[idx].as_str(), score))
+                .collect()
+        })
+        .collect()
+    }
+}
+
+/// Compute the CSLS hubness penalties of the candidates.
+///
+/// The penalties are computed within the candidate space, see
+/// `SimilarityMeasure::Csls`.
+///
+/// Returns an array with the penalty of each candidate at its word
+/// index, the other words have a penalty of zero.
+fn candidate_hubness(
+    storage: &StorageWrap,
+    candidates: &Candidates,
+    n_words: usize,
+) -> Array1<f32> {
+    let mut hubness = Array1::zeros(n_words);
+    let n_candidates = candidates.n_candidates();
+    for start in (0..n_candidates).step_by(CHUNK_SIZE) {
+        let end = (start + CHUNK_SIZE).min(n_candidates);
+        let vectors = candidates.embeddings(storage, start, end);
+        let skips = (start..end)
+            .map(|candidate| vec![candidates.word_idx(candidate)])
+            .collect::<Vec<_>>();
+        let chunk_hubness = mean_top_k_cosines(storage, candidates, vectors.view(), &skips);
+        for (skip, penalty) in skips.iter().zip(chunk_hubness) {
+            hubness[skip[0]] = penalty;
+        }
+    }
+
+    hubness
+}
+
+/// Compute the mean cosine similarity of each vector to its
+/// `CSLS_NEIGHBORS` nearest candidates.
+fn mean_top_k_cosines(
+    storage: &StorageWrap,
+    candidates: &Candidates,
+    vectors: ArrayView2<f32>,
+    skips: &[Vec<usize>],
+) -> Vec<f32> {
+    top_k_words(
+        storage,
+        candidates,
+        vectors,
+        skips,
+        CSLS_NEIGHBORS,
+        |query_idx, dots, _| dots.column(query_idx).to_owned(),
+    )
+    .into_iter()
+    .map(|neighbors| {
+        if neighbors.is_empty() {
+            0.
+        } else {
+            neighbors.iter().map(|(_, cosine)| cosine).sum::<f32>() / neighbors.len() as f32
+        }
+    })
+    .collect()
+}
+
+/// Find the indices and scores of the `k` highest-scoring words for
+/// each query.
+///
+/// The dot products of the embeddings of the candidates and the rows of
+/// `vectors` are computed in chunks, which are processed in parallel.
+/// `score` computes the scores of query `i` from the dot products of a
+/// chunk and the word indices of the chunk. The words with indices in
+/// `skips[i]` are not returned for query `i`.
+pub fn top_k_words<F>(
+    storage: &impl WordChunks,
+    candidates: &Candidates,
+    vectors: ArrayView2<f32>,
+    skips: &[Vec<usize>],
+    k: usize,
+    score: F,
+) -> Vec<Vec<(usize, f32)>>
+where
+    F: Fn(usize, ArrayView2<f32>, &[usize]) -> Array1<f32> + Sync,
+{
+    if skips.is_empty() {
+        return Vec::new();
+    }
+
+    let n_candidates = candidates.n_candidates();
+    let starts = (0..n_candidates).step_by(CHUNK_SIZE).collect::<Vec<_>>();
+    let chunk_candidates = starts
+        .into_par_iter()
+        .map(|start| {
+            let end = (start + CHUNK_SIZE).min(n_candidates);
+            let dots = candidates.embeddings(storage, start, end).dot(&vectors.t());
+            let words = (start..end)
+                .map(|candidate| candidates.word_idx(candidate))
+                .collect::<Vec<_>>();
+            skips
+                .iter()
+                .enumerate()
+                .map(|(query_idx, skip)| {
+                    let skip = candidates.offsets(skip, start, end);
+                    top_k(score(query_idx, dots.view(), &words).view(), &skip, k)
+                        .into_iter()
+                        .map(|(idx, word_score)| (words[idx], word_score))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // Merge the candidates of the chunks. Candidates are in word order
+    // when scores are tied, so ties are still broken by word index.
+    (0..skips.len())
+        .map(|query_idx| {
+            let (indices, scores): (Vec<_>, Vec<_>) = chunk_candidates
+                .iter()
+                .flat_map(|candidates| candidates[query_idx].iter().cloned())
+                .unzip();
+            top_k(ArrayView1::from(&scores), &[], k)
+                .into_iter()
+                .map(|(idx, word_score)| (indices[idx], word_score))
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,266 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use anyhow::{anyhow, Context, Error, Result};
+use clap::{Arg, ArgMatches};
+use finalfusion::prelude::*;
+use finalfusion::vocab::Vocab;
+use ndarray::{Array1, Array2, ArrayView1, ArrayView2};
+
+use crate::neighbors::{top_k_words, Candidates};
+use crate::util::l2_normalize;
+
+const OBJECTIVE: &str = "objective";
+
+/// Smoothing constant of 3CosMul, avoids division by zero.
+const COS_MUL_EPSILON: f32 = 0.001;
+
+/// Objective for answering analogy queries *a:b :: c:?*.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnalogyObjective {
+    /// 3CosAdd: *cos(x, b - a + c)*.
+    Add,
+
+    /// 3CosMul (Levy & Goldberg, 2014): *cos(x, b) cos(x, c) / (cos(x, a) + ε)*,
+    /// with cosine similarities shifted to *[0, 1]*.
+    Mul,
+
+    /// Pair direction: *cos(x - c, b - a)*.
+    PairDirection,
+}
+
+impl AnalogyObjective {
+    pub fn new_clap_arg() -> Arg<'static, 'static> {
+        Arg::with_name(OBJECTIVE)
+            .long("objective")
+            .value_name("OBJECTIVE")
+            .takes_value(true)
+            .default_value("add")
+            .possible_values(&["add", "mul", "pair-direction"])
+            .help("Analogy objective")
+    }
+
+    pub fn parse_clap_matches(matches: &ArgMatches) -> Result<Self> {
+        let objective = matches
+            .value_of(OBJECTIVE)
+            .map(|s| {
+                AnalogyObjective::try_from(s)
+                    .context(format!("Cannot parse analogy objective: {}", s))
+            })
+            .transpose()?
+            .unwrap();
+        Ok(objective)
+    }
+
+    /// Check whether scores of this objective are cosine similarities.
+    pub fn is_cosine(&self) -> bool {
+        use self::AnalogyObjective::*;
+        match self {
+            Add | PairDirection => true,
+            Mul => false,
+        }
+    }
+
+    /// Answer the analogy query *a:b :: c:?*.
+    ///
+    /// The query words for which `remove` is `true` are not returned as
+    /// answers. If an embedding could not be computed for a query word,
+    /// the error contains for each word whether its embedding was found.
+    /// If `restrict` is set, only the first `restrict` words of the
+    /// vocabulary are considered as answers.
+    pub fn analogy<'a>(
+        &self,
+        embeddings: &'a Embeddings<VocabWrap, StorageViewWrap>,
+        query: [&str; 3],
+        remove: [bool; 3],
+        limit: usize,
+        restrict: Option<usize>,
+    ) -> Result<Vec<AnalogyResult<'a>>, [bool; 3]> {
+        let n_words = embeddings.vocab().words_len();
+        let candidates = Candidates::Prefix(restrict.map(|n| n.min(n_words)).unwrap_or(n_words));
+        self.analogies(embeddings, &candidates, &[query], remove, limit)
+            .pop()
+            .expect("No result for analogy query")
+    }
+
+    /// Answer a batch of analogy queries *a:b :: c:?*.
+    ///
+    /// The queries are answered together, only `candidates` are returned
+    /// as answers. See `analogy` for the meaning of the other arguments.
+    /// The results are in query order.
+    pub fn analogies<'a>(
+        &self,
+        embeddings: &'a Embeddings<VocabWrap, StorageViewWrap>,
+        candidates: &Candidates,
+        queries: &[[&str; 3]],
+        remove: [bool; 3],
+        limit: usize,
+    ) -> Vec<Result<Vec<AnalogyResult<'a>>, [bool; 3]>> {
+        let vocab = embeddings.vocab();
+
+        // The vectors of all queries are stacked, so that their dot
+        // products with the word embeddings are computed at once.
+        let mut vectors = Vec::new();
+        let mut c_directions = Vec::new();
+        let mut skips = Vec::new();
+        let mut found = Vec::with_capacity(queries.len());
+        for query in queries {
+            let query_embeddings = [
+                embeddings.embedding(query[0]),
+                embeddings.embedding(query[1]),
+                embeddings.embedding(query[2]),
+            ];
+            let (a, b, c) = match &query_embeddings {
+                [Some(a), Some(b), Some(c)] => (a.view(), b.view(), c.view()),
+                _ => {
+                    found.push(Err([
+                        query_embeddings[0].is_some(),
+                        query_embeddings[1].is_some(),
+                        query_embeddings[2].is_some(),
+                    ]));
+                    continue;
+                }
+            };
+
+            match self {
+                AnalogyObjective::Add => {
+                    let mut target = &b - &a + c;
+                    l2_normalize(target.view_mut());
+                    vectors.extend(target.iter());
+                    c_directions.push(0.);
+                }
+                AnalogyObjective::Mul => {
+                    vectors.extend(a.iter().chain(&b).chain(&c));
+                    c_directions.push(0.);
+                }
+                AnalogyObjective::PairDirection => {
+                    let mut direction = &b - &a;
+                    l2_normalize(direction.view_mut());
+                    vectors.extend(direction.iter().chain(&c));
+                    c_directions.push(c.dot(&direction));
+                }
+            }
+
+            skips.push(
+                query
+                    .iter()
+                    .zip(&remove)
+                    .filter(|(_, remove)| **remove)
+                    .filter_map(|(word, _)| vocab.idx(word).and_then(|idx| idx.word()))
+                    .collect::<Vec<_>>(),
+            );
+            found.push(Ok(()));
+        }
+
+        let n_vectors = self.n_vectors();
+        let vectors = Array2::from_shape_vec((skips.len() * n_vectors, embeddings.dims()), vectors)
+            .expect("Query vectors do not match the embedding dimensionality");
+
+        let mut results = top_k_words(
+            embeddings.storage(),
+            candidates,
+            vectors.view(),
+            &skips,
+            limit,
+            |query_idx, dots, _| self.scores(dots, query_idx * n_vectors, c_directions[query_idx]),
+        )
+        .into_iter();
+
+        found
+            .into_iter()
+            .map(|found| {
+                found.map(|()| {
+                    results
+                        .next()
+                        .expect("No result for analogy query")
+                        .into_iter()
+                        .map(|(idx, score)| AnalogyResult {
+                            word: &vocab.words()[idx],
+                            score,
+                        })
+                        .collect()
+                })
+            })
+            .collect()
+    }
+
+    /// The number of query vectors per analogy query.
+    fn n_vectors(&self) -> usize {
+        use self::AnalogyObjective::*;
+        match self {
+            Add => 1,
+            Mul => 3,
+            PairDirection => 2,
+        }
+    }
+
+    /// Compute the scores of word embeddings from their dot products
+    /// with the vectors of a query, starting at column `offset`.
+    ///
+    /// `c_direction` is the dot product of *c* and the pair direction,
+    /// it is only used by the pair direction objective.
+    fn scores(&self, dots: ArrayView2<f32>, offset: usize, c_direction: f32) -> Array1<f32> {
+        match self {
+            AnalogyObjective::Add => dots.column(offset).to_owned(),
+            AnalogyObjective::Mul => {
+                let shift = |sims: ArrayView1<f32>| sims.mapv(|sim| (sim + 1.) / 2.);
+                let sims_a = shift(dots.column(offset));
+                let sims_b = shift(dots.column(offset + 1));
+                let sims_c = shift(dots.column(offset + 2));
+                sims_b * sims_c / (sims_a + COS_MUL_EPSILON)
+            }
+            AnalogyObjective::PairDirection => {
+                let sims_c = dots.column(offset + 1);
+                let mut scores = dots.column(offset).to_owned();
+                // Word embeddings are unit vectors, so |x - c|² = 2 - 2 cos(x, c).
+                scores.zip_mut_with(&sims_c, |score, &sim_c| {
+                    let dist = (2. - 2. * sim_c).max(0.).sqrt();
+                    *score = if dist == 0. {
+                        0.
+                    } else {
+                        (*score - c_direction) / dist
+                    };
+                });
+                scores
+            }
+        }
+    }
+}
+
+impl TryFrom<&str> for AnalogyObjective {
+    type Error = Error;
+
+    fn try_from(objective: &str) -> Result<Self> {
+        use self::AnalogyObjective::*;
+
+        match objective {
+            "add" => Ok(Add),
+            "mul" => Ok(Mul),
+            "pair-direction" => Ok(PairDirection),
+            unknown => Err(anyhow!("Unknown analogy objective: {}", unknown)),
+        }
+    }
+}
+
+impl fmt::Display for AnalogyObjective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::AnalogyObjective::*;
+        let s = match self {
+            Add => "add",
+            Mul => "mul",
+            PairDirection => "pair-direction",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// Answer to an analogy query.
+#[derive(Clone, Debug)]
+pub struct AnalogyResult<'a> {
+    pub word: &'a str,
+
+    /// Score of the answer, a cosine similarity for objectives where
+    /// `AnalogyObjective::is_cosine` is `true`.
+    pub score: f32,
+}
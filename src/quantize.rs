@@ -13,7 +13,7 @@ use reductive::pq::Pq;
 #[cfg(feature = "opq")]
 use reductive::pq::{GaussianOpq, Opq};
 
-use crate::io::{read_embeddings_view, write_embeddings, EmbeddingFormat};
+use crate::io::{check_output, read_embeddings_view, write_embeddings, EmbeddingFormat, Overwrite};
 use crate::FinalfusionApp;
 
 // Option constants
@@ -37,6 +37,7 @@ pub struct QuantizeApp {
     n_subquantizers: Option<usize>,
     n_threads: usize,
     output_filename: String,
+    overwrite: Overwrite,
     quantizer: String,
     quantizer_bits: u32,
 }
@@ -75,6 +76,7 @@ impl FinalfusionApp for QuantizeApp {
                     .takes_value(true)
                     .default_value("8"),
             )
+            .arg(Overwrite::new_clap_arg())
             .arg(
                 Arg::with_name(INPUT_FORMAT)
                     .short("f")
@@ -82,6 +84,7 @@ impl FinalfusionApp for QuantizeApp {
                     .value_name("FORMAT")
                     .takes_value(true)
                     .possible_values(&[
+                        "auto",
                         "fasttext",
                         "finalfusion",
                         "floret",
@@ -89,7 +92,7 @@ impl FinalfusionApp for QuantizeApp {
                         "textdims",
                         "word2vec",
                     ])
-                    .default_value("word2vec"),
+                    .default_value("auto"),
             )
             .arg(
                 Arg::with_name(N_ITERATIONS)
@@ -189,13 +192,14 @@ impl FinalfusionApp for QuantizeApp {
             n_subquantizers,
             n_threads,
             output_filename,
+            overwrite: Overwrite::parse_clap_matches(matches),
             quantizer,
             quantizer_bits,
         })
     }
 
     fn run(&self) -> Result<()> {
-        env_logger::init();
+        check_output(&self.input_filename, &self.output_filename, self.overwrite)?;
 
         ThreadPoolBuilder::new()
             .num_threads(self.n_threads)
@@ -212,6 +216,7 @@ impl FinalfusionApp for QuantizeApp {
             &self.output_filename,
             EmbeddingFormat::FinalFusion,
             false,
+            self.overwrite,
         )?;
 
         print_loss(embeddings.storage(), quantized_embeddings.storage());
@@ -9,7 +9,7 @@ use finalfusion::storage::{NdArray, QuantizedArray, Reconstruct};
 use finalfusion::vocab::Vocab;
 use ndarray::{s, Array2};
 
-use crate::io::{write_embeddings, EmbeddingFormat};
+use crate::io::{check_output, write_embeddings, EmbeddingFormat, Overwrite};
 use crate::util::l2_normalize_array;
 use crate::FinalfusionApp;
 
@@ -20,6 +20,7 @@ static OUTPUT: &str = "OUTPUT";
 pub struct ReconstructApp {
     input_filename: String,
     output_filename: String,
+    overwrite: Overwrite,
 }
 
 impl FinalfusionApp for ReconstructApp {
@@ -38,6 +39,7 @@ impl FinalfusionApp for ReconstructApp {
                     .index(2)
                     .required(true),
             )
+            .arg(Overwrite::new_clap_arg())
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
@@ -48,10 +50,13 @@ impl FinalfusionApp for ReconstructApp {
         Ok(ReconstructApp {
             input_filename,
             output_filename,
+            overwrite: Overwrite::parse_clap_matches(matches),
         })
     }
 
     fn run(&self) -> Result<()> {
+        check_output(&self.input_filename, &self.output_filename, self.overwrite)?;
+
         let f = File::open(&self.input_filename).context("Cannot open embeddings file")?;
         let mut reader = BufReader::new(f);
         let embeddings: Embeddings<VocabWrap, QuantizedArray> =
@@ -76,6 +81,7 @@ impl FinalfusionApp for ReconstructApp {
             &self.output_filename,
             EmbeddingFormat::FinalFusion,
             false,
+            self.overwrite,
         )
     }
 }
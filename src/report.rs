@@ -0,0 +1,312 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Write;
+
+use anyhow::{anyhow, Context, Error, Result};
+use clap::{Arg, ArgMatches};
+use serde::Serialize;
+
+const OUTPUT_FORMAT: &str = "output_format";
+
+/// Format of reports, such as summaries and evaluation results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Text,
+}
+
+impl ReportFormat {
+    pub fn new_clap_arg() -> Arg<'static, 'static> {
+        Arg::with_name(OUTPUT_FORMAT)
+            .long("output-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["json", "text"])
+            .default_value("text")
+            .help("Output format")
+    }
+
+    pub fn parse_clap_matches(matches: &ArgMatches) -> Result<Self> {
+        let format = matches
+            .value_of(OUTPUT_FORMAT)
+            .map(|v| {
+                ReportFormat::try_from(v).context(format!("Cannot parse output format: {}", v))
+            })
+            .transpose()?
+            .unwrap();
+        Ok(format)
+    }
+}
+
+impl TryFrom<&str> for ReportFormat {
+    type Error = Error;
+
+    fn try_from(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(ReportFormat::Json),
+            "text" => Ok(ReportFormat::Text),
+            unknown => Err(anyhow!("Unknown output format: {}", unknown)),
+        }
+    }
+}
+
+impl fmt::Display for ReportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ReportFormat::Json => "json",
+            ReportFormat::Text => "text",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// Format of the results of similarity and analogy queries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryFormat {
+    Csv,
+    Jsonl,
+    Text,
+
+    /// Tab-separated values, with backslash escapes for backslashes,
+    /// tabs and line breaks in fields.
+    Tsv,
+}
+
+impl QueryFormat {
+    pub fn new_clap_arg() -> Arg<'static, 'static> {
+        Arg::with_name(OUTPUT_FORMAT)
+            .long("output-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .possible_values(&["csv", "jsonl", "text", "tsv"])
+            .default_value("text")
+            .help("Output format")
+    }
+
+    pub fn parse_clap_matches(matches: &ArgMatches) -> Result<Self> {
+        let format = matches
+            .value_of(OUTPUT_FORMAT)
+            .map(|v| QueryFormat::try_from(v).context(format!("Cannot parse output format: {}", v)))
+            .transpose()?
+            .unwrap();
+        Ok(format)
+    }
+}
+
+impl TryFrom<&str> for QueryFormat {
+    type Error = Error;
+
+    fn try_from(format: &str) -> Result<Self> {
+        match format {
+            "csv" => Ok(QueryFormat::Csv),
+            "jsonl" => Ok(QueryFormat::Jsonl),
+            "text" => Ok(QueryFormat::Text),
+            "tsv" => Ok(QueryFormat::Tsv),
+            unknown => Err(anyhow!("Unknown output format: {}", unknown)),
+        }
+    }
+}
+
+impl fmt::Display for QueryFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            QueryFormat::Csv => "csv",
+            QueryFormat::Jsonl => "jsonl",
+            QueryFormat::Text => "text",
+            QueryFormat::Tsv => "tsv",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// Result of a query: a neighbor or words without an embedding.
+#[derive(Serialize)]
+struct QueryRecord<'a> {
+    query: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rank: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    neighbor: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    missing: Option<&'a [&'a str]>,
+}
+
+/// Writer for the results of similarity and analogy queries.
+///
+/// The text format only consists of neighbors and scores, words without
+/// an embedding are reported on standard error. The other formats have
+/// a record for every neighbor or query with missing embeddings.
+pub struct QueryWriter<W> {
+    format: QueryFormat,
+    writer: W,
+}
+
+impl<W> QueryWriter<W>
+where
+    W: Write,
+{
+    /// Construct a writer, the header is written for tabular formats.
+    pub fn new(format: QueryFormat, writer: W) -> Result<Self> {
+        let mut writer = QueryWriter { format, writer };
+        let header = ["query", "rank", "neighbor", "score", "missing"];
+        match format {
+            QueryFormat::Csv | QueryFormat::Tsv => writer.write_row(&header)?,
+            QueryFormat::Jsonl | QueryFormat::Text => (),
+        }
+
+        Ok(writer)
+    }
+
+    /// Write the neighbors of a query, in order of decreasing score.
+    pub fn write_neighbors<'a>(
+        &mut self,
+        query: &str,
+        neighbors: impl IntoIterator<Item = (&'a str, f32)>,
+    ) -> Result<()> {
+        for (rank, (neighbor, score)) in neighbors.into_iter().enumerate() {
+            if self.format == QueryFormat::Text {
+                writeln!(self.writer, "{}\t{}", neighbor, score)
+                    .context("Cannot write query result")?;
+                continue;
+            }
+
+            self.write_record(QueryRecord {
+                query,
+                rank: Some(rank + 1),
+                neighbor: Some(neighbor),
+                score: Some(score),
+                missing: None,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a query for which embeddings could not be computed.
+    pub fn write_missing(&mut self, query: &str, missing: &[&str]) -> Result<()> {
+        if self.format == QueryFormat::Text {
+            eprintln!("Could not compute embedding(s) for: {}", missing.join(", "));
+            return Ok(());
+        }
+
+        self.write_record(QueryRecord {
+            query,
+            rank: None,
+            neighbor: None,
+            score: None,
+            missing: Some(missing),
+        })
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().context("Cannot flush output")
+    }
+
+    fn write_record(&mut self, record: QueryRecord) -> Result<()> {
+        if self.format == QueryFormat::Jsonl {
+            serde_json::to_writer(&mut self.writer, &record)
+                .context("Cannot serialize query result")?;
+            return writeln!(self.writer).context("Cannot write query result");
+        }
+
+        let rank = record.rank.map(|rank| rank.to_string()).unwrap_or_default();
+        let score = record
+            .score
+            .map(|score| score.to_string())
+            .unwrap_or_default();
+        let missing = record.missing.unwrap_or_default().join(" ");
+        self.write_row(&[
+            record.query,
+            &rank,
+            record.neighbor.unwrap_or_default(),
+            &score,
+            &missing,
+        ])
+    }
+
+    fn write_row(&mut self, fields: &[&str]) -> Result<()> {
+        let row = match self.format {
+            QueryFormat::Csv => fields
+                .iter()
+                .map(|&field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => fields
+                .iter()
+                .map(|&field| tsv_field(field))
+                .collect::<Vec<_>>()
+                .join("\t"),
+        };
+
+        writeln!(self.writer, "{}", row).context("Cannot write query result")
+    }
+}
+
+/// Quote a CSV field if necessary.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Escape backslashes, tabs and line breaks in a TSV field.
+fn tsv_field(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, tsv_field};
+
+    #[test]
+    fn plain_fields_are_not_quoted() {
+        assert_eq!(csv_field("berlin"), "berlin");
+        assert_eq!(csv_field("new york"), "new york");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn special_fields_are_quoted() {
+        assert_eq!(csv_field("1,000"), "\"1,000\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("carriage\rreturn"), "\"carriage\rreturn\"");
+    }
+
+    #[test]
+    fn quotes_are_escaped() {
+        assert_eq!(csv_field("\"quoted\""), "\"\"\"quoted\"\"\"");
+        assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn plain_tsv_fields_are_not_escaped() {
+        assert_eq!(tsv_field("new york"), "new york");
+        assert_eq!(tsv_field("1,000"), "1,000");
+        assert_eq!(tsv_field(""), "");
+    }
+
+    #[test]
+    fn tsv_separators_are_escaped() {
+        assert_eq!(tsv_field("a\tb"), "a\\tb");
+        assert_eq!(tsv_field("line\nbreak"), "line\\nbreak");
+        assert_eq!(tsv_field("carriage\rreturn"), "carriage\\rreturn");
+        assert_eq!(tsv_field("back\\slash"), "back\\\\slash");
+    }
+}
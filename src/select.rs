@@ -2,17 +2,20 @@ use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::io::BufRead;
 
-use anyhow::{bail, Context, Error, Result};
+use anyhow::{bail, ensure, Context, Error, Result};
 use clap::{App, Arg, ArgMatches};
 use finalfusion::embeddings::Embeddings;
 use finalfusion::norms::NdNorms;
-use finalfusion::storage::{NdArray, StorageWrap};
-use finalfusion::vocab::{SimpleVocab, Vocab, VocabWrap};
+use finalfusion::storage::{NdArray, Storage, StorageWrap};
+use finalfusion::subword::BucketIndexer;
+use finalfusion::vocab::{FastTextSubwordVocab, SimpleVocab, Vocab, VocabWrap};
 use ndarray::{Array1, Array2};
 use stdinout::Input;
 
 use super::FinalfusionApp;
-use crate::io::{read_embeddings, write_embeddings, EmbeddingFormat};
+use crate::io::{
+    check_output, read_embeddings, write_embeddings, EmbeddingFormat, Overwrite, STDIO_FILENAME,
+};
 
 const IGNORE_UNKNOWN: &str = "IGNORE_UNKNOWN";
 const INPUT_EMBEDDINGS: &str = "INPUT_EMBEDDINGS";
@@ -27,6 +30,7 @@ pub struct SelectApp {
     input_format: EmbeddingFormat,
     output_filename: String,
     output_format: EmbeddingFormat,
+    overwrite: Overwrite,
     select_input: Input,
 }
 
@@ -40,12 +44,14 @@ impl FinalfusionApp for SelectApp {
                     .long("ignore-unknown")
                     .help("Ignore words for which no embedding is available"),
             )
+            .arg(Overwrite::new_clap_arg())
             .arg(
                 Arg::with_name(INPUT_FORMAT)
                     .short("f")
                     .value_name("FORMAT")
                     .takes_value(true)
                     .possible_values(&[
+                        "auto",
                         "fasttext",
                         "finalfusion",
                         "finalfusion_mmap",
@@ -54,7 +60,7 @@ impl FinalfusionApp for SelectApp {
                         "textdims",
                         "word2vec",
                     ])
-                    .default_value("finalfusion")
+                    .default_value("auto")
                     .help("Input format"),
             )
             .arg(
@@ -62,19 +68,19 @@ impl FinalfusionApp for SelectApp {
                     .short("t")
                     .value_name("FORMAT")
                     .takes_value(true)
-                    .possible_values(&["finalfusion", "text", "textdims", "word2vec"])
+                    .possible_values(&["fasttext", "finalfusion", "text", "textdims", "word2vec"])
                     .default_value("finalfusion")
                     .help("Output format"),
             )
             .arg(
                 Arg::with_name(INPUT_EMBEDDINGS)
-                    .help("Input embeddings")
+                    .help("Input embeddings (- for standard input)")
                     .index(1)
                     .required(true),
             )
             .arg(
                 Arg::with_name(OUTPUT_EMBEDDINGS)
-                    .help("Output embeddings")
+                    .help("Output embeddings (- for standard output)")
                     .index(2)
                     .required(true),
             )
@@ -85,6 +91,10 @@ impl FinalfusionApp for SelectApp {
         let input_filename = matches.value_of(INPUT_EMBEDDINGS).unwrap().to_owned();
         let output_filename = matches.value_of(OUTPUT_EMBEDDINGS).unwrap().to_owned();
         let select_input = Input::from(matches.value_of("SELECT"));
+        ensure!(
+            input_filename != STDIO_FILENAME || matches.is_present(SELECT),
+            "Words to select must be read from a file when embeddings are read from standard input"
+        );
 
         let ignore_unknown = matches.is_present(IGNORE_UNKNOWN);
 
@@ -112,23 +122,33 @@ impl FinalfusionApp for SelectApp {
             input_format,
             output_filename,
             output_format,
+            overwrite: Overwrite::parse_clap_matches(matches),
             select_input,
         })
     }
 
     fn run(&self) -> Result<()> {
+        check_output(&self.input_filename, &self.output_filename, self.overwrite)?;
+
         let embeddings = read_embeddings(&self.input_filename, self.input_format)
             .context("Cannot read embeddings")?;
 
         let select = self.read_words(&embeddings)?;
 
-        let output_embeddings = copy_select_embeddings(&embeddings, select)?;
+        let output_embeddings = match (self.output_format, embeddings.vocab()) {
+            // Retain subwords, so that the embeddings can be written in fastText format.
+            (EmbeddingFormat::FastText, VocabWrap::FastTextSubwordVocab(vocab)) => {
+                copy_select_fasttext_embeddings(&embeddings, vocab, select)?
+            }
+            _ => copy_select_embeddings(&embeddings, select)?,
+        };
 
         write_embeddings(
             &output_embeddings,
             &self.output_filename,
             self.output_format,
             true,
+            self.overwrite,
         )
     }
 }
@@ -137,8 +157,21 @@ impl SelectApp {
     fn read_words(
         &self,
         embeddings: &Embeddings<VocabWrap, StorageWrap>,
-    ) -> Result<HashSet<String>, Error> {
-        let mut words = HashSet::new();
+    ) -> Result<Vec<String>, Error> {
+        // Words are copied with their subword vocabulary when writing
+        // fastText embeddings, so words that are only known through
+        // their subwords cannot be selected.
+        let in_vocab_only = matches!(
+            (self.output_format, embeddings.vocab()),
+            (
+                EmbeddingFormat::FastText,
+                VocabWrap::FastTextSubwordVocab(_)
+            )
+        );
+
+        // Words are selected in the order of the input, without duplicates.
+        let mut seen = HashSet::new();
+        let mut words = Vec::new();
 
         for word in self
             .select_input
@@ -148,16 +181,20 @@ impl SelectApp {
         {
             let word = word?;
 
-            match embeddings.vocab().idx(&word) {
-                Some(_) => {
-                    words.insert(word);
-                }
-                None => {
-                    if !self.ignore_unknown {
-                        bail!("Cannot get embedding for: {}", word)
-                    }
-                }
+            let idx = embeddings.vocab().idx(&word);
+            let known = if in_vocab_only {
+                idx.and_then(|idx| idx.word()).is_some()
+            } else {
+                idx.is_some()
             };
+
+            if known {
+                if seen.insert(word.clone()) {
+                    words.push(word);
+                }
+            } else if !self.ignore_unknown {
+                bail!("Cannot get embedding for: {}", word)
+            }
         }
 
         Ok(words)
@@ -166,7 +203,7 @@ impl SelectApp {
 
 fn copy_select_embeddings(
     embeddings: &Embeddings<VocabWrap, StorageWrap>,
-    select: HashSet<String>,
+    select: Vec<String>,
 ) -> Result<Embeddings<VocabWrap, StorageWrap>> {
     let mut selected_vocab = Vec::new();
     let mut selected_storage = Array2::zeros((select.len(), embeddings.dims()));
@@ -194,3 +231,57 @@ fn copy_select_embeddings(
     )
     .into())
 }
+
+fn copy_select_fasttext_embeddings(
+    embeddings: &Embeddings<VocabWrap, StorageWrap>,
+    vocab: &FastTextSubwordVocab,
+    select: Vec<String>,
+) -> Result<Embeddings<VocabWrap, StorageWrap>> {
+    let n_buckets = vocab.indexer().buckets();
+    let mut selected_vocab = Vec::new();
+    let mut selected_storage = Array2::zeros((select.len() + n_buckets, embeddings.dims()));
+    let mut selected_norms = Array1::zeros((select.len(),));
+
+    for (idx, word) in select.into_iter().enumerate() {
+        let word_idx = match vocab.idx(&word).and_then(|idx| idx.word()) {
+            Some(word_idx) => word_idx,
+            None => bail!(
+                "Cannot select word without embedding in fastText format: {}",
+                word
+            ),
+        };
+
+        selected_storage
+            .row_mut(idx)
+            .assign(&embeddings.storage().embedding(word_idx).view());
+        selected_norms[idx] = embeddings
+            .norms()
+            .map(|norms| norms[word_idx])
+            .unwrap_or(1.);
+
+        selected_vocab.push(word);
+    }
+
+    let n_words = selected_vocab.len();
+    for bucket in 0..n_buckets {
+        selected_storage.row_mut(n_words + bucket).assign(
+            &embeddings
+                .storage()
+                .embedding(vocab.words_len() + bucket)
+                .view(),
+        );
+    }
+
+    Ok(Embeddings::new(
+        None,
+        FastTextSubwordVocab::new(
+            selected_vocab,
+            vocab.min_n(),
+            vocab.max_n(),
+            *vocab.indexer(),
+        ),
+        NdArray::from(selected_storage),
+        NdNorms::new(selected_norms),
+    )
+    .into())
+}
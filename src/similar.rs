@@ -1,33 +1,65 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::io::BufRead;
+use std::io::{stdout, BufRead, BufWriter, Write};
 
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use clap::{App, Arg, ArgMatches};
-use finalfusion::similarity::WordSimilarity;
+use finalfusion::prelude::*;
+use finalfusion::vocab::Vocab;
+use ndarray::{Array1, Array2};
+use rayon::ThreadPoolBuilder;
 use stdinout::Input;
 
 use super::FinalfusionApp;
-use crate::io::{read_embeddings_view, EmbeddingFormat};
+use crate::io::{read_embeddings, EmbeddingFormat};
+use crate::neighbors::{read_word_indices, Candidates, NearestNeighbors, Queries};
+use crate::report::{QueryFormat, QueryWriter};
 use crate::similarity::SimilarityMeasure;
+use crate::util::l2_normalize;
 
 pub struct SimilarApp {
+    batch_size: usize,
+    candidates_filename: Option<String>,
     embeddings_filename: String,
     embedding_format: EmbeddingFormat,
+    exclude_filename: Option<String>,
+    hide_case_variants: bool,
     input: Option<String>,
     k: usize,
+    min_similarity: Option<f32>,
+    n_threads: usize,
+    output_format: QueryFormat,
+    restrict_vocab: Option<usize>,
     similarity: SimilarityMeasure,
+    vectors: bool,
 }
 
 impl FinalfusionApp for SimilarApp {
     fn app() -> App<'static, 'static> {
         App::new("similar")
             .about("Find words that are similar to a given word")
+            .arg(
+                Arg::with_name("batch_size")
+                    .long("batch-size")
+                    .value_name("N")
+                    .help("Number of queries that are processed at once")
+                    .takes_value(true)
+                    .default_value("1000"),
+            )
+            .arg(
+                Arg::with_name("candidates")
+                    .long("candidates")
+                    .value_name("FILE")
+                    .help("Only return words from FILE (one word per line)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("format")
                     .short("f")
                     .value_name("FORMAT")
                     .takes_value(true)
                     .possible_values(&[
+                        "auto",
                         "fasttext",
                         "finalfusion",
                         "finalfusion_mmap",
@@ -35,24 +67,68 @@ impl FinalfusionApp for SimilarApp {
                         "textdims",
                         "word2vec",
                     ])
-                    .default_value("finalfusion"),
+                    .default_value("auto"),
+            )
+            .arg(
+                Arg::with_name("exclude")
+                    .long("exclude")
+                    .value_name("FILE")
+                    .help("Do not return words from FILE (one word per line)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("hide_case_variants")
+                    .long("hide-case-variants")
+                    .help("Do not return words with the same lowercase form as a query word"),
+            )
+            .arg(
+                Arg::with_name("min_similarity")
+                    .long("min-similarity")
+                    .value_name("SIMILARITY")
+                    .help("Only return words with at least this similarity")
+                    .takes_value(true),
             )
             .arg(
                 Arg::with_name("neighbors")
                     .short("k")
                     .value_name("K")
-                    .help("Return K nearest neighbors")
-                    .takes_value(true)
-                    .default_value("10"),
+                    .help(
+                        "Return K nearest neighbors (default: 10, unlimited with --min-similarity)",
+                    )
+                    .takes_value(true),
+            )
+            .arg(QueryFormat::new_clap_arg())
+            .arg(
+                Arg::with_name("restrict_vocab")
+                    .long("restrict-vocab")
+                    .value_name("N")
+                    .help("Only return the N most frequent words")
+                    .takes_value(true),
             )
             .arg(SimilarityMeasure::new_clap_arg())
+            .arg(
+                Arg::with_name("threads")
+                    .long("threads")
+                    .value_name("N")
+                    .help("Number of threads (default: logical_cpus / 2)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("vectors")
+                    .long("vectors")
+                    .help("Input lines are vectors of whitespace-separated components"),
+            )
             .arg(
                 Arg::with_name("EMBEDDINGS")
                     .help("Embeddings file")
                     .index(1)
                     .required(true),
             )
-            .arg(Arg::with_name("INPUT").help("Input words").index(2))
+            .arg(
+                Arg::with_name("INPUT")
+                    .help("Input words, or queries such as: +paris +germany -france")
+                    .index(2),
+            )
     }
 
     fn parse(matches: &ArgMatches) -> Result<Self> {
@@ -69,6 +145,36 @@ impl FinalfusionApp for SimilarApp {
             .transpose()?
             .unwrap();
 
+        let batch_size = matches
+            .value_of("batch_size")
+            .map(|v| v.parse().context(format!("Cannot parse batch size: {}", v)))
+            .transpose()?
+            .unwrap();
+        ensure!(batch_size > 0, "The batch size must be at least 1");
+
+        let n_threads = matches
+            .value_of("threads")
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse number of threads: {}", v))
+            })
+            .transpose()?
+            .unwrap_or(num_cpus::get() / 2);
+
+        let candidates_filename = matches.value_of("candidates").map(ToOwned::to_owned);
+
+        let exclude_filename = matches.value_of("exclude").map(ToOwned::to_owned);
+
+        let min_similarity = matches
+            .value_of("min_similarity")
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse minimum similarity: {}", v))
+            })
+            .transpose()?;
+
+        // Without a number of neighbors, all words with the minimum
+        // similarity are returned.
         let k = matches
             .value_of("neighbors")
             .map(|k| {
@@ -76,45 +182,331 @@ impl FinalfusionApp for SimilarApp {
                     .context(format!("Cannot parse number of neighbors: {}", k))
             })
             .transpose()?
-            .unwrap();
+            .unwrap_or(if min_similarity.is_some() {
+                usize::MAX
+            } else {
+                10
+            });
+
+        let output_format = QueryFormat::parse_clap_matches(matches)?;
+
+        let restrict_vocab = matches
+            .value_of("restrict_vocab")
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse vocabulary restriction: {}", v))
+            })
+            .transpose()?;
 
         let similarity = SimilarityMeasure::parse_clap_matches(matches)?;
+        // CSLS hubness penalties require the neighbors of every candidate.
+        ensure!(
+            similarity != SimilarityMeasure::Csls
+                || restrict_vocab.is_some()
+                || candidates_filename.is_some(),
+            "CSLS compares every candidate with every other candidate, \
+             use --restrict-vocab or --candidates to limit the number of candidates"
+        );
 
         Ok(SimilarApp {
+            batch_size,
+            candidates_filename,
             embeddings_filename,
             embedding_format,
+            exclude_filename,
+            hide_case_variants: matches.is_present("hide_case_variants"),
             input,
             k,
+            min_similarity,
+            n_threads,
+            output_format,
+            restrict_vocab,
             similarity,
+            vectors: matches.is_present("vectors"),
         })
     }
 
     fn run(&self) -> Result<()> {
-        let embeddings = read_embeddings_view(&self.embeddings_filename, self.embedding_format)
+        ThreadPoolBuilder::new()
+            .num_threads(self.n_threads)
+            .build_global()
+            .unwrap();
+
+        let embeddings = read_embeddings(&self.embeddings_filename, self.embedding_format)
             .context("Cannot read embeddings")?;
+        let candidates = Candidates::from_options(
+            embeddings.vocab(),
+            self.restrict_vocab,
+            self.candidates_filename.as_deref(),
+        )?;
+        let neighbors = NearestNeighbors::new(&embeddings, candidates, self.similarity)?;
+        let exclusions = self.exclusions(embeddings.vocab())?;
 
         let input = Input::from(self.input.as_ref());
         let reader = input.buf_read().context("Cannot open input for reading")?;
 
-        for line in reader.lines() {
+        let stdout = stdout();
+        let mut writer = QueryWriter::new(self.output_format, BufWriter::new(stdout.lock()))?;
+
+        let mut batch = Vec::with_capacity(self.batch_size);
+        for (line_idx, line) in reader.lines().enumerate() {
             let line = line.context("Cannot read line")?.trim().to_owned();
             if line.is_empty() {
                 continue;
             }
 
-            let results = match embeddings.word_similarity(&line, self.k) {
-                Some(results) => results,
-                None => {
-                    eprintln!("Could not compute embedding for: {}", line);
-                    continue;
-                }
+            batch.push((line_idx + 1, line));
+            if batch.len() == self.batch_size {
+                self.process_batch(&embeddings, &neighbors, &exclusions, &batch, &mut writer)?;
+                batch.clear();
+            }
+        }
+
+        self.process_batch(&embeddings, &neighbors, &exclusions, &batch, &mut writer)?;
+
+        writer.flush()
+    }
+}
+
+impl SimilarApp {
+    /// Get the words that should not be returned as neighbors.
+    fn exclusions(&self, vocab: &VocabWrap) -> Result<Exclusions> {
+        let words = match &self.exclude_filename {
+            Some(filename) => read_word_indices(vocab, filename)?,
+            None => Vec::new(),
+        };
+
+        let case_variants = if self.hide_case_variants {
+            let mut case_variants = HashMap::<_, Vec<_>>::new();
+            for (idx, word) in vocab.words().iter().enumerate() {
+                case_variants
+                    .entry(word.to_lowercase())
+                    .or_default()
+                    .push(idx);
+            }
+            Some(case_variants)
+        } else {
+            None
+        };
+
+        Ok(Exclusions {
+            words,
+            case_variants,
+        })
+    }
+
+    /// Answer a batch of queries, given as line numbers and lines.
+    fn process_batch(
+        &self,
+        embeddings: &Embeddings<VocabWrap, StorageWrap>,
+        neighbors: &NearestNeighbors,
+        exclusions: &Exclusions,
+        batch: &[(usize, String)],
+        writer: &mut QueryWriter<impl Write>,
+    ) -> Result<()> {
+        // Dot products and Euclidean distances use unnormalized embeddings.
+        let unnormalized = matches!(
+            self.similarity,
+            SimilarityMeasure::Dot | SimilarityMeasure::Euclidean
+        );
+
+        let mut vectors = Array2::zeros((batch.len(), embeddings.dims()));
+        let mut norms = Vec::with_capacity(batch.len());
+        let mut skips = Vec::with_capacity(batch.len());
+        let mut missing = Vec::with_capacity(batch.len());
+        for ((line_no, line), mut query) in batch.iter().zip(vectors.outer_iter_mut()) {
+            let result = if self.vectors {
+                parse_vector(line, embeddings.dims())
+                    .context(format!("Invalid query vector on line {}", line_no))
+                    .map(|vector| Ok((vector, Vec::new())))?
+            } else {
+                word_query(embeddings, line, unnormalized)
             };
 
-            for similar in results {
-                println!("{}\t{}", similar.word(), self.similarity.as_f32(&similar));
+            // Queries with missing embeddings keep a zero vector, their
+            // results are discarded.
+            match result {
+                Ok((vector, query_words)) => {
+                    query.assign(&vector);
+                    norms.push(l2_normalize(query));
+                    skips.push(exclusions.skip(embeddings.vocab(), &query_words));
+                    missing.push(None);
+                }
+                Err(words) => {
+                    norms.push(0.);
+                    skips.push(Vec::new());
+                    missing.push(Some(words));
+                }
+            }
+        }
+
+        let queries = Queries {
+            vectors,
+            norms,
+            skips,
+        };
+        let results = neighbors.nearest_words(&queries, self.k, self.min_similarity);
+        for (((_, line), results), missing) in batch.iter().zip(results).zip(missing) {
+            match missing {
+                Some(missing) => writer.write_missing(line, &missing)?,
+                None => writer.write_neighbors(line, results)?,
             }
         }
 
         Ok(())
     }
 }
+
+/// Words that are not returned as neighbors.
+struct Exclusions {
+    /// Words that are excluded for every query.
+    words: Vec<usize>,
+
+    /// Word indices by lowercase form, only present when case variants
+    /// of query words are excluded.
+    case_variants: Option<HashMap<String, Vec<usize>>>,
+}
+
+impl Exclusions {
+    /// Get the indices of the words that are excluded for a query.
+    fn skip(&self, vocab: &VocabWrap, query_words: &[&str]) -> Vec<usize> {
+        let mut skip = self.words.clone();
+        for word in query_words {
+            skip.extend(vocab.idx(word).and_then(|idx| idx.word()));
+            if let Some(case_variants) = &self.case_variants {
+                skip.extend(
+                    case_variants
+                        .get(&word.to_lowercase())
+                        .into_iter()
+                        .flatten(),
+                );
+            }
+        }
+
+        skip
+    }
+}
+
+/// Compute the query vector of a line with words.
+///
+/// Returns the sum of the query embeddings and the query words. If
+/// embeddings cannot be computed for some query words, these words are
+/// returned as the error.
+fn word_query<'a>(
+    embeddings: &Embeddings<VocabWrap, StorageWrap>,
+    line: &'a str,
+    unnormalized: bool,
+) -> Result<(Array1<f32>, Vec<&'a str>), Vec<&'a str>> {
+    // Words such as `-LRB-` or `+1` are looked up as-is, the line is
+    // only parsed as a compound query when it is not a known word.
+    let known_word = embeddings
+        .vocab()
+        .idx(line)
+        .and_then(|idx| idx.word())
+        .is_some();
+    let (positive, negative) = if known_word {
+        None
+    } else {
+        parse_compound_query(line)
+    }
+    .unwrap_or_else(|| (vec![line], Vec::new()));
+    let query = sum_embeddings(embeddings, &positive, &negative, unnormalized)?;
+
+    Ok((query, positive.into_iter().chain(negative).collect()))
+}
+
+/// Parse a query with positive and negative words.
+///
+/// In such queries, every word is prefixed by `+` (positive) or `-`
+/// (negative), e.g. `+paris +germany -france`. Returns `None` if the
+/// line is not such a query or if it does not have a positive word.
+fn parse_compound_query(line: &str) -> Option<(Vec<&str>, Vec<&str>)> {
+    let mut positive = Vec::new();
+    let mut negative = Vec::new();
+    for token in line.split_whitespace() {
+        if token.len() < 2 {
+            return None;
+        }
+
+        match token.split_at(1) {
+            ("+", word) => positive.push(word),
+            ("-", word) => negative.push(word),
+            _ => return None,
+        }
+    }
+
+    if positive.is_empty() {
+        return None;
+    }
+
+    Some((positive, negative))
+}
+
+/// Parse a query vector with `dims` whitespace-separated components.
+fn parse_vector(line: &str, dims: usize) -> Result<Array1<f32>> {
+    let vector = line
+        .split_whitespace()
+        .map(|v| {
+            v.parse()
+                .context(format!("Cannot parse vector component: {}", v))
+        })
+        .collect::<Result<Vec<f32>>>()?;
+
+    ensure!(
+        vector.len() == dims,
+        "Vector has {} components, the embeddings have {} dimensions",
+        vector.len(),
+        dims
+    );
+
+    Ok(vector.into())
+}
+
+/// Sum the positive embeddings and subtract the negative embeddings.
+///
+/// The normalized embeddings are summed, unless `unnormalized` is set.
+/// If embeddings cannot be computed for some words, these words are
+/// returned as the error.
+fn sum_embeddings<'a>(
+    embeddings: &Embeddings<VocabWrap, StorageWrap>,
+    positive: &[&'a str],
+    negative: &[&'a str],
+    unnormalized: bool,
+) -> Result<Array1<f32>, Vec<&'a str>> {
+    let mut sum = Array1::zeros(embeddings.dims());
+    let mut missing = Vec::new();
+    for (words, sign) in &[(positive, 1f32), (negative, -1f32)] {
+        for &word in words.iter() {
+            match embeddings.embedding_with_norm(word) {
+                Some(embedding) => {
+                    let scale = if unnormalized { embedding.norm } else { 1. };
+                    sum.scaled_add(sign * scale, &embedding.embedding)
+                }
+                None => missing.push(word),
+            }
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(missing);
+    }
+
+    Ok(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_compound_query;
+
+    #[test]
+    fn compound_query_needs_positive_word() {
+        assert_eq!(
+            parse_compound_query("+paris +germany -france"),
+            Some((vec!["paris", "germany"], vec!["france"]))
+        );
+        assert_eq!(parse_compound_query("+1"), Some((vec!["1"], vec![])));
+        assert_eq!(parse_compound_query("-LRB-"), None);
+        assert_eq!(parse_compound_query("--"), None);
+        assert_eq!(parse_compound_query("+paris france"), None);
+    }
+}
@@ -3,7 +3,7 @@ use std::fmt;
 
 use anyhow::{anyhow, Context, Error, Result};
 use clap::{Arg, ArgMatches};
-use finalfusion::similarity::WordSimilarityResult;
+use ndarray::ArrayView1;
 
 const SIMILARITY: &str = "similarity";
 
@@ -11,6 +11,22 @@ const SIMILARITY: &str = "similarity";
 pub enum SimilarityMeasure {
     Angular,
     Cosine,
+
+    /// Cross-domain similarity local scaling (Conneau et al., 2018).
+    ///
+    /// This is the single-space variant: Conneau et al. penalize a
+    /// target word by its mean similarity to its nearest source
+    /// vectors. Since queries and candidates come from one embedding
+    /// space here, a candidate is penalized by its mean similarity to
+    /// its nearest candidates instead. Queries are penalized by their
+    /// mean similarity to their nearest candidates, as in the paper.
+    Csls,
+
+    /// Dot product of the unnormalized embeddings.
+    Dot,
+
+    /// Negated Euclidean distance of the unnormalized embeddings.
+    Euclidean,
 }
 
 impl SimilarityMeasure {
@@ -21,8 +37,8 @@ impl SimilarityMeasure {
             .value_name("SIMILARITY")
             .takes_value(true)
             .default_value("cosine")
-            .possible_values(&["angular", "cosine"])
-            .help("Similarity measure")
+            .possible_values(&["angular", "cosine", "csls", "dot", "euclidean"])
+            .help("Similarity measure (euclidean reports the negated distance)")
     }
 
     pub fn parse_clap_matches(matches: &ArgMatches) -> Result<Self> {
@@ -37,11 +53,53 @@ impl SimilarityMeasure {
         Ok(measure)
     }
 
-    pub fn as_f32(&self, result: &WordSimilarityResult) -> f32 {
+    /// Check whether the measure only depends on the cosine similarity.
+    pub fn is_cosine_based(&self) -> bool {
+        use self::SimilarityMeasure::*;
+        match self {
+            Angular | Cosine => true,
+            Csls | Dot | Euclidean => false,
+        }
+    }
+
+    /// Compute the similarity of two embeddings.
+    ///
+    /// The embeddings do not have to be normalized. CSLS hubness
+    /// penalties are not applied.
+    pub fn similarity(&self, u: ArrayView1<f32>, v: ArrayView1<f32>) -> f32 {
+        let u_norm = u.dot(&u).sqrt();
+        let v_norm = v.dot(&v).sqrt();
+        let cosine = if u_norm == 0. || v_norm == 0. {
+            0.
+        } else {
+            (u.dot(&v) / (u_norm * v_norm)).clamp(-1., 1.)
+        };
+
+        self.combine_parts(cosine, (u_norm, v_norm), (0., 0.))
+    }
+
+    /// Convert the cosine similarity of two unit vectors to this
+    /// similarity measure.
+    pub fn convert_cosine(&self, cosine: f32) -> f32 {
+        self.combine_parts(cosine, (1., 1.), (0., 0.))
+    }
+
+    /// Compute the similarity of two embeddings from their cosine
+    /// similarity, norms and CSLS hubness penalties.
+    ///
+    /// The hubness penalty of an embedding is the mean cosine similarity
+    /// to its nearest neighbors, it is only used by CSLS.
+    pub fn combine_parts(&self, cosine: f32, norms: (f32, f32), hubness: (f32, f32)) -> f32 {
         use self::SimilarityMeasure::*;
         match self {
-            Angular => result.angular_similarity(),
-            Cosine => result.cosine_similarity(),
+            Angular => 1. - (cosine.clamp(-1., 1.).acos() / std::f32::consts::PI),
+            Cosine => cosine,
+            Csls => 2. * cosine - hubness.0 - hubness.1,
+            Dot => norms.0 * norms.1 * cosine,
+            Euclidean => {
+                let (u, v) = norms;
+                -(u * u + v * v - 2. * u * v * cosine).max(0.).sqrt()
+            }
         }
     }
 }
@@ -55,6 +113,9 @@ impl TryFrom<&str> for SimilarityMeasure {
         match format {
             "angular" => Ok(Angular),
             "cosine" => Ok(Cosine),
+            "csls" => Ok(Csls),
+            "dot" => Ok(Dot),
+            "euclidean" => Ok(Euclidean),
             unknown => Err(anyhow!("Unknown similarity measure: {}", unknown)),
         }
     }
@@ -66,6 +127,9 @@ impl fmt::Display for SimilarityMeasure {
         let s = match self {
             Angular => "angular",
             Cosine => "cosine",
+            Csls => "csls",
+            Dot => "dot",
+            Euclidean => "euclidean",
         };
 
         f.write_str(s)
@@ -0,0 +1,120 @@
+use rand::Rng;
+
+/// Bootstrap confidence interval of an accuracy.
+///
+/// `correct` contains for each instance whether it was answered
+/// correctly. The instances are resampled `n_samples` times and the
+/// interval is given by the percentiles of the resampled accuracies.
+/// Returns `None` when there are no instances.
+pub fn bootstrap_accuracy(
+    correct: &[bool],
+    n_samples: usize,
+    confidence: f64,
+    rng: &mut impl Rng,
+) -> Option<(f64, f64)> {
+    if correct.is_empty() || n_samples == 0 {
+        return None;
+    }
+
+    let mut accuracies = (0..n_samples)
+        .map(|_| {
+            let n_correct = (0..correct.len())
+                .filter(|_| correct[rng.gen_range(0..correct.len())])
+                .count();
+            n_correct as f64 / correct.len() as f64
+        })
+        .collect::<Vec<_>>();
+    accuracies.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let alpha = (1. - confidence) / 2.;
+    let lower = ((alpha * n_samples as f64).floor() as usize).min(n_samples - 1);
+    let upper = (((1. - alpha) * n_samples as f64).ceil() as usize)
+        .saturating_sub(1)
+        .min(n_samples - 1);
+
+    Some((accuracies[lower], accuracies[upper]))
+}
+
+/// Two-sided exact McNemar test.
+///
+/// `b` and `c` are the numbers of instances that only the first and
+/// only the second model answered correctly. Returns the p-value of
+/// the null hypothesis that both models have the same accuracy.
+pub fn mcnemar_exact(b: usize, c: usize) -> f64 {
+    let n = b + c;
+    if n == 0 {
+        return 1.;
+    }
+
+    // Under the null hypothesis, b ~ Binomial(n, 0.5). Binomial
+    // coefficients are computed in log space to avoid overflow.
+    let mut ln_binom = 0f64;
+    let mut p = 0f64;
+    for i in 0..=b.min(c) {
+        if i > 0 {
+            ln_binom += ((n - i + 1) as f64).ln() - (i as f64).ln();
+        }
+        p += (ln_binom - n as f64 * std::f64::consts::LN_2).exp();
+    }
+
+    (2. * p).min(1.)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{bootstrap_accuracy, mcnemar_exact};
+
+    #[test]
+    fn bootstrap_constant_accuracy() {
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(
+            bootstrap_accuracy(&[true; 10], 100, 0.95, &mut rng),
+            Some((1., 1.))
+        );
+        assert_eq!(
+            bootstrap_accuracy(&[false; 10], 100, 0.95, &mut rng),
+            Some((0., 0.))
+        );
+    }
+
+    #[test]
+    fn bootstrap_without_instances_or_samples() {
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(bootstrap_accuracy(&[], 100, 0.95, &mut rng), None);
+        assert_eq!(bootstrap_accuracy(&[true, false], 0, 0.95, &mut rng), None);
+    }
+
+    #[test]
+    fn bootstrap_interval_contains_accuracy() {
+        let correct = (0..200).map(|i| i % 4 != 0).collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(42);
+        let (lower, upper) = bootstrap_accuracy(&correct, 1000, 0.95, &mut rng).unwrap();
+        assert!(lower < 0.75 && 0.75 < upper);
+        assert!(lower > 0.65 && upper < 0.85);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let (narrow_lower, narrow_upper) =
+            bootstrap_accuracy(&correct, 1000, 0.5, &mut rng).unwrap();
+        assert!(lower <= narrow_lower && narrow_upper <= upper);
+    }
+
+    #[test]
+    fn mcnemar_exact_p_values() {
+        assert_eq!(mcnemar_exact(0, 0), 1.);
+        assert_eq!(mcnemar_exact(1, 1), 1.);
+        assert!((mcnemar_exact(0, 5) - 0.0625).abs() < 1e-12);
+        // 2 * (C(10, 0) + C(10, 1) + C(10, 2)) / 2^10
+        assert!((mcnemar_exact(2, 8) - 0.109375).abs() < 1e-12);
+        assert_eq!(mcnemar_exact(2, 8), mcnemar_exact(8, 2));
+    }
+
+    #[test]
+    fn mcnemar_exact_large_counts() {
+        assert_eq!(mcnemar_exact(5000, 5000), 1.);
+        let p = mcnemar_exact(4000, 6000);
+        assert!(p.is_finite() && p < 1e-10);
+    }
+}
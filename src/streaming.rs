@@ -0,0 +1,458 @@
+use std::borrow::Cow;
+use std::io::{BufRead, Write};
+use std::str;
+
+use anyhow::{bail, ensure, Context, Result};
+use ndarray::ArrayViewMut1;
+
+use crate::io::EmbeddingFormat;
+use crate::util::l2_normalize;
+
+/// Check whether a format can be converted record by record.
+pub fn is_streamable(format: EmbeddingFormat) -> bool {
+    use self::EmbeddingFormat::*;
+    matches!(format, Text | TextDims | Word2Vec)
+}
+
+/// Check whether converting between two formats requires the number of
+/// embeddings before the first embedding is written.
+///
+/// This is the case when the output has a header that the input does
+/// not provide.
+pub fn requires_count(input_format: EmbeddingFormat, output_format: EmbeddingFormat) -> bool {
+    input_format == EmbeddingFormat::Text && output_format != EmbeddingFormat::Text
+}
+
+/// Count the embeddings in a file in the text format.
+///
+/// This requires an additional pass over the input, so it should only
+/// be used when `requires_count` is true.
+pub fn count_text_embeddings(reader: impl BufRead, lossy: bool) -> Result<usize> {
+    let mut reader = RecordReader::new(reader, EmbeddingFormat::Text, lossy)?;
+    let mut n_embeddings = 0;
+    while reader.read_record()? {
+        n_embeddings += 1;
+    }
+
+    Ok(n_embeddings)
+}
+
+/// Convert embeddings between text-based formats one embedding at a time.
+///
+/// The conversion has the same semantics as reading the embeddings and
+/// writing them with finalfusion: embeddings are written l2-normalized,
+/// unless `unnormalize` is used. `n_embeddings` must be provided when the
+/// input does not have a header and the output does.
+pub fn convert_streaming(
+    reader: impl BufRead,
+    input_format: EmbeddingFormat,
+    write: &mut dyn Write,
+    output_format: EmbeddingFormat,
+    n_embeddings: Option<usize>,
+    lossy: bool,
+    unnormalize: bool,
+) -> Result<()> {
+    ensure!(
+        is_streamable(input_format) && is_streamable(output_format),
+        "Cannot stream conversion from {} to {}",
+        input_format,
+        output_format
+    );
+
+    let mut reader = RecordReader::new(reader, input_format, lossy)?;
+    let n_embeddings = match reader.header {
+        Some((n_embeddings, _)) => n_embeddings,
+        None if output_format == EmbeddingFormat::Text => 0,
+        None => n_embeddings.context("The number of embeddings is required to write a header")?,
+    };
+
+    let has_first = reader.read_record()?;
+    let dims = reader.dims.unwrap_or(0);
+
+    if output_format != EmbeddingFormat::Text {
+        writeln!(write, "{} {}", n_embeddings, dims).context("Cannot write header")?;
+    }
+
+    let mut n_written = 0;
+    let mut more = has_first;
+    while more {
+        if !unnormalize {
+            l2_normalize(ArrayViewMut1::from(reader.embedding.as_mut_slice()));
+        }
+
+        write_record(write, output_format, &reader.word, &reader.embedding)
+            .context(format!("Cannot write embedding for: {}", reader.word))?;
+        n_written += 1;
+
+        more = reader.read_record()?;
+    }
+
+    // The count is checked when the input has a header, as well as when
+    // a header was written.
+    let counted = reader.header.is_some() || output_format != EmbeddingFormat::Text;
+    ensure!(
+        !counted || n_written == n_embeddings,
+        "Expected {} embeddings, but read {}",
+        n_embeddings,
+        n_written
+    );
+
+    Ok(())
+}
+
+fn write_record(
+    write: &mut dyn Write,
+    format: EmbeddingFormat,
+    word: &str,
+    embedding: &[f32],
+) -> Result<()> {
+    match format {
+        EmbeddingFormat::Text | EmbeddingFormat::TextDims => {
+            let embedding_str = embedding
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" ");
+            writeln!(write, "{} {}", word, embedding_str)?;
+        }
+        EmbeddingFormat::Word2Vec => {
+            write!(write, "{} ", word)?;
+            for v in embedding {
+                write.write_all(&v.to_le_bytes())?;
+            }
+            write.write_all(b"\n")?;
+        }
+        format => bail!("Cannot stream embeddings in {} format", format),
+    }
+
+    Ok(())
+}
+
+/// Reader for embeddings in a text-based format.
+///
+/// The buffers for the word and embedding of the last record are reused.
+struct RecordReader<R> {
+    reader: R,
+    format: EmbeddingFormat,
+    lossy: bool,
+    header: Option<(usize, usize)>,
+    dims: Option<usize>,
+    buf: Vec<u8>,
+    word: String,
+    embedding: Vec<f32>,
+    line: usize,
+}
+
+impl<R> RecordReader<R>
+where
+    R: BufRead,
+{
+    fn new(reader: R, format: EmbeddingFormat, lossy: bool) -> Result<Self> {
+        let mut reader = RecordReader {
+            reader,
+            format,
+            lossy,
+            header: None,
+            dims: None,
+            buf: Vec::new(),
+            word: String::new(),
+            embedding: Vec::new(),
+            line: 0,
+        };
+
+        if format != EmbeddingFormat::Text {
+            let (n_embeddings, dims) = reader.read_header()?;
+            reader.header = Some((n_embeddings, dims));
+            reader.dims = Some(dims);
+        }
+
+        Ok(reader)
+    }
+
+    fn read_header(&mut self) -> Result<(usize, usize)> {
+        self.buf.clear();
+        self.reader
+            .read_until(b'\n', &mut self.buf)
+            .context("Cannot read header")?;
+        self.line += 1;
+
+        let header = str::from_utf8(&self.buf).context("Header is not valid UTF-8")?;
+        let fields = header.split_whitespace().collect::<Vec<_>>();
+        ensure!(fields.len() == 2, "Malformed header: {}", header.trim());
+        let n_embeddings = fields[0]
+            .parse()
+            .context(format!("Cannot parse number of embeddings: {}", fields[0]))?;
+        let dims = fields[1]
+            .parse()
+            .context(format!("Cannot parse dimensionality: {}", fields[1]))?;
+
+        Ok((n_embeddings, dims))
+    }
+
+    /// Read the next record, returns `false` when there are no records left.
+    fn read_record(&mut self) -> Result<bool> {
+        let more = match self.format {
+            EmbeddingFormat::Text | EmbeddingFormat::TextDims => self.read_text_record()?,
+            EmbeddingFormat::Word2Vec => self.read_word2vec_record()?,
+            format => bail!("Cannot stream embeddings in {} format", format),
+        };
+
+        if more {
+            // Without a header, the first embedding determines the dimensionality.
+            let dims = *self.dims.get_or_insert(self.embedding.len());
+            ensure!(
+                self.embedding.len() == dims,
+                "Embedding for '{}' has dimensionality {}, expected {}",
+                self.word,
+                self.embedding.len(),
+                dims
+            );
+        }
+
+        Ok(more)
+    }
+
+    fn read_text_record(&mut self) -> Result<bool> {
+        self.buf.clear();
+        if self
+            .reader
+            .read_until(b'\n', &mut self.buf)
+            .context("Cannot read embedding")?
+            == 0
+        {
+            return Ok(false);
+        }
+        self.line += 1;
+
+        let line = decode(&self.buf, self.lossy)
+            .context(format!("Line {} is not valid UTF-8", self.line))?;
+        let mut fields = line.split_whitespace();
+        // Empty lines are rejected, as in the finalfusion text readers.
+        let word = fields
+            .next()
+            .context(format!("Line {} is empty", self.line))?;
+
+        self.word.clear();
+        self.word.push_str(word);
+
+        self.embedding.clear();
+        for field in fields {
+            self.embedding.push(field.parse().context(format!(
+                "Cannot parse embedding component on line {}: {}",
+                self.line, field
+            ))?);
+        }
+
+        Ok(true)
+    }
+
+    fn read_word2vec_record(&mut self) -> Result<bool> {
+        self.buf.clear();
+        self.reader
+            .read_until(b' ', &mut self.buf)
+            .context("Cannot read word")?;
+
+        // Embeddings are separated by a newline in most word2vec files.
+        let start = self
+            .buf
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(self.buf.len());
+        let end = if self.buf.last() == Some(&b' ') {
+            self.buf.len() - 1
+        } else {
+            self.buf.len()
+        };
+        if start >= end {
+            return Ok(false);
+        }
+
+        let word = decode(&self.buf[start..end], self.lossy).context("Word is not valid UTF-8")?;
+        self.word.clear();
+        self.word.push_str(&word);
+
+        let dims = self.dims.unwrap_or(0);
+        self.buf.resize(dims * std::mem::size_of::<f32>(), 0);
+        self.reader
+            .read_exact(&mut self.buf)
+            .context(format!("Cannot read embedding for: {}", self.word))?;
+
+        self.embedding.clear();
+        self.embedding.extend(
+            self.buf
+                .chunks_exact(std::mem::size_of::<f32>())
+                .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]])),
+        );
+
+        Ok(true)
+    }
+}
+
+fn decode(data: &[u8], lossy: bool) -> Result<Cow<'_, str>> {
+    if lossy {
+        Ok(String::from_utf8_lossy(data))
+    } else {
+        Ok(Cow::Borrowed(str::from_utf8(data)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use finalfusion::compat::text::{ReadText, ReadTextDims, WriteText, WriteTextDims};
+    use finalfusion::compat::word2vec::{ReadWord2Vec, WriteWord2Vec};
+    use finalfusion::prelude::*;
+    use finalfusion::storage::NdArray;
+    use finalfusion::vocab::SimpleVocab;
+
+    use super::{convert_streaming, count_text_embeddings, requires_count};
+    use crate::io::EmbeddingFormat::{self, *};
+
+    const TEXT: &str = "foo 1 2 3\nbar -0.5 0.25 4\nbaz 0 0 1e-3\n";
+
+    fn in_memory(
+        mut input: &[u8],
+        input_format: EmbeddingFormat,
+        output_format: EmbeddingFormat,
+    ) -> Result<Vec<u8>> {
+        let embeddings: Embeddings<SimpleVocab, NdArray> = match input_format {
+            Text => ReadText::read_text(&mut input)?,
+            TextDims => ReadTextDims::read_text_dims(&mut input)?,
+            Word2Vec => ReadWord2Vec::read_word2vec_binary(&mut input)?,
+            format => unreachable!("{} is not a text-based format", format),
+        };
+
+        let mut output = Vec::new();
+        match output_format {
+            Text => embeddings.write_text(&mut output, false)?,
+            TextDims => embeddings.write_text_dims(&mut output, false)?,
+            Word2Vec => embeddings.write_word2vec_binary(&mut output, false)?,
+            format => unreachable!("{} is not a text-based format", format),
+        }
+
+        Ok(output)
+    }
+
+    fn streaming(
+        input: &[u8],
+        input_format: EmbeddingFormat,
+        output_format: EmbeddingFormat,
+    ) -> Result<Vec<u8>> {
+        let n_embeddings = if requires_count(input_format, output_format) {
+            Some(count_text_embeddings(input, false)?)
+        } else {
+            None
+        };
+
+        let mut output = Vec::new();
+        convert_streaming(
+            input,
+            input_format,
+            &mut output,
+            output_format,
+            n_embeddings,
+            false,
+            false,
+        )?;
+
+        Ok(output)
+    }
+
+    fn assert_same_output(
+        input: &[u8],
+        input_format: EmbeddingFormat,
+        output_format: EmbeddingFormat,
+    ) {
+        assert_eq!(
+            streaming(input, input_format, output_format).unwrap(),
+            in_memory(input, input_format, output_format).unwrap(),
+            "{} -> {}",
+            input_format,
+            output_format
+        );
+    }
+
+    fn assert_both_fail(
+        input: &[u8],
+        input_format: EmbeddingFormat,
+        output_format: EmbeddingFormat,
+    ) {
+        assert!(
+            in_memory(input, input_format, output_format).is_err(),
+            "in-memory conversion of {:?} did not fail",
+            String::from_utf8_lossy(input)
+        );
+        assert!(
+            streaming(input, input_format, output_format).is_err(),
+            "streaming conversion of {:?} did not fail",
+            String::from_utf8_lossy(input)
+        );
+    }
+
+    fn word2vec() -> Vec<u8> {
+        in_memory(TEXT.as_bytes(), Text, Word2Vec).unwrap()
+    }
+
+    fn textdims() -> Vec<u8> {
+        in_memory(TEXT.as_bytes(), Text, TextDims).unwrap()
+    }
+
+    #[test]
+    fn text_to_word2vec() {
+        assert_same_output(TEXT.as_bytes(), Text, Word2Vec);
+        assert_same_output(TEXT.as_bytes(), Text, TextDims);
+        assert_same_output(TEXT.as_bytes(), Text, Text);
+    }
+
+    #[test]
+    fn word2vec_to_text() {
+        let word2vec = word2vec();
+        assert_same_output(&word2vec, Word2Vec, Text);
+        assert_same_output(&word2vec, Word2Vec, TextDims);
+        assert_same_output(&word2vec, Word2Vec, Word2Vec);
+    }
+
+    #[test]
+    fn textdims_conversions() {
+        let textdims = textdims();
+        assert_same_output(&textdims, TextDims, Text);
+        assert_same_output(&textdims, TextDims, Word2Vec);
+        assert_same_output(&textdims, TextDims, TextDims);
+    }
+
+    #[test]
+    fn missing_trailing_newline() {
+        let text = TEXT.trim_end().as_bytes();
+        assert_same_output(text, Text, Word2Vec);
+        assert_same_output(text, Text, Text);
+
+        let textdims = textdims();
+        assert_same_output(&textdims[..textdims.len() - 1], TextDims, Word2Vec);
+    }
+
+    #[test]
+    fn malformed_text_records() {
+        // Inconsistent dimensionality.
+        assert_both_fail(b"foo 1 2 3\nbar 1 2\n", Text, Word2Vec);
+        // Unparsable component.
+        assert_both_fail(b"foo 1 2 3\nbar 1 x 3\n", Text, Text);
+        // Empty line.
+        assert_both_fail(b"foo 1 2 3\n\nbar 1 2 3\n", Text, TextDims);
+    }
+
+    #[test]
+    fn malformed_textdims_records() {
+        // Fewer embeddings than in the header.
+        assert_both_fail(b"3 2\nfoo 1 2\nbar 3 4\n", TextDims, Text);
+        // Dimensionality that differs from the header.
+        assert_both_fail(b"2 2\nfoo 1 2 3\nbar 3 4 5\n", TextDims, Word2Vec);
+        // Malformed header.
+        assert_both_fail(b"2\nfoo 1 2\nbar 3 4\n", TextDims, Text);
+    }
+
+    #[test]
+    fn truncated_word2vec() {
+        let word2vec = word2vec();
+        assert_both_fail(&word2vec[..word2vec.len() - 3], Word2Vec, Text);
+    }
+}
@@ -1,7 +1,8 @@
+use std::cmp::Ordering;
 #[cfg(feature = "intel-mkl-amd")]
 use std::os::raw::c_int;
 
-use ndarray::{Array1, ArrayViewMut1, ArrayViewMut2};
+use ndarray::{Array1, ArrayView1, ArrayViewMut1, ArrayViewMut2};
 
 pub fn l2_normalize(mut v: ArrayViewMut1<f32>) -> f32 {
     let norm = v.dot(&v).sqrt();
@@ -22,6 +23,35 @@ pub fn l2_normalize_array(mut v: ArrayViewMut2<f32>) -> Array1<f32> {
     norms.into()
 }
 
+/// Get the indices and scores of the `k` highest scores.
+///
+/// NaN scores and the indices in `skip` are ignored.
+pub fn top_k(scores: ArrayView1<f32>, skip: &[usize], k: usize) -> Vec<(usize, f32)> {
+    let mut candidates = scores
+        .iter()
+        .cloned()
+        .enumerate()
+        .filter(|(idx, score)| !score.is_nan() && !skip.contains(idx))
+        .collect::<Vec<_>>();
+
+    let cmp = |l: &(usize, f32), r: &(usize, f32)| {
+        r.1.partial_cmp(&l.1)
+            .unwrap_or(Ordering::Equal)
+            .then(l.0.cmp(&r.0))
+    };
+
+    if k < candidates.len() {
+        if k == 0 {
+            return Vec::new();
+        }
+        candidates.select_nth_unstable_by(k - 1, cmp);
+        candidates.truncate(k);
+    }
+
+    candidates.sort_unstable_by(cmp);
+    candidates
+}
+
 #[cfg(feature = "intel-mkl-amd")]
 #[allow(dead_code)]
 #[no_mangle]
@@ -0,0 +1,532 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+use anyhow::{bail, ensure, Context, Result};
+use clap::{App, Arg, ArgMatches};
+use finalfusion::prelude::*;
+use finalfusion::storage::Storage;
+use finalfusion::vocab::Vocab;
+
+use crate::io::{read_embeddings, EmbeddingFormat, EmbeddingsReader, STDIO_FILENAME};
+use crate::FinalfusionApp;
+
+// Option constants
+static INPUT_FORMAT: &str = "input_format";
+static NORM_TOLERANCE: &str = "norm_tolerance";
+
+// Argument constants
+static INPUT: &str = "INPUT";
+
+/// Maximum number of failing items that is printed per check.
+const MAX_EXAMPLES: usize = 10;
+
+pub struct ValidateApp {
+    input_filename: String,
+    input_format: EmbeddingFormat,
+    norm_tolerance: f32,
+}
+
+impl FinalfusionApp for ValidateApp {
+    fn app() -> App<'static, 'static> {
+        App::new("validate")
+            .about("Check the integrity of embeddings")
+            .arg(
+                Arg::with_name(INPUT)
+                    .help("Embeddings file")
+                    .index(1)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name(INPUT_FORMAT)
+                    .short("f")
+                    .long("from")
+                    .value_name("FORMAT")
+                    .takes_value(true)
+                    .possible_values(&[
+                        "auto",
+                        "fasttext",
+                        "finalfusion",
+                        "finalfusion_mmap",
+                        "floret",
+                        "text",
+                        "textdims",
+                        "word2vec",
+                    ])
+                    .default_value("auto"),
+            )
+            .arg(
+                Arg::with_name(NORM_TOLERANCE)
+                    .long("norm-tolerance")
+                    .value_name("TOLERANCE")
+                    .help("Maximum deviation of the norm of a stored word embedding from 1")
+                    .takes_value(true)
+                    .default_value("0.001"),
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Self> {
+        let input_filename = matches.value_of(INPUT).unwrap().to_owned();
+        let input_format = matches
+            .value_of(INPUT_FORMAT)
+            .map(|v| {
+                EmbeddingFormat::try_from(v).context(format!("Cannot parse input format: {}", v))
+            })
+            .transpose()?
+            .unwrap();
+        let norm_tolerance = matches
+            .value_of(NORM_TOLERANCE)
+            .map(|v| {
+                v.parse()
+                    .context(format!("Cannot parse norm tolerance: {}", v))
+            })
+            .transpose()?
+            .unwrap();
+
+        Ok(ValidateApp {
+            input_filename,
+            input_format,
+            norm_tolerance,
+        })
+    }
+
+    fn run(&self) -> Result<()> {
+        let checks = self.checks()?;
+
+        for check in &checks {
+            print!("{}", check);
+        }
+
+        let n_failed = checks
+            .iter()
+            .filter(|check| check.status == Status::Fail)
+            .count();
+        if n_failed != 0 {
+            bail!("{} of {} checks failed", n_failed, checks.len());
+        }
+
+        Ok(())
+    }
+}
+
+impl ValidateApp {
+    fn checks(&self) -> Result<Vec<Check>> {
+        let (reader, input_format) =
+            EmbeddingsReader::open(&self.input_filename, self.input_format)?;
+
+        // finalfusion vocabularies cannot be constructed with duplicate
+        // words and the text readers fail on empty words, so the words of
+        // text-based formats are checked before the embeddings are read.
+        if is_text_based(input_format) {
+            ensure!(
+                self.input_filename != STDIO_FILENAME,
+                "Cannot validate {} embeddings from standard input",
+                input_format
+            );
+
+            let words = read_words(reader, input_format)
+                .context(format!("Cannot read words from {}", self.input_filename))?;
+            let vocab_check = check_words(&words);
+            if vocab_check.status == Status::Fail {
+                return Ok(vec![
+                    vocab_check,
+                    Check::skip("embeddings", "cannot read embeddings with invalid words"),
+                ]);
+            }
+        } else {
+            drop(reader);
+        }
+
+        let embeddings = read_embeddings(&self.input_filename, input_format)
+            .context("Cannot read embeddings")?;
+
+        Ok(vec![
+            check_shape(&embeddings),
+            check_words(embeddings.vocab().words()),
+            check_values(&embeddings),
+            check_zero_rows(&embeddings),
+            check_norms(&embeddings, self.norm_tolerance),
+        ])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Fail,
+    Pass,
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Status::Fail => "FAIL",
+            Status::Pass => "PASS",
+            Status::Skip => "SKIP",
+        };
+
+        f.write_str(s)
+    }
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    message: String,
+    examples: Vec<String>,
+}
+
+impl Check {
+    fn skip(name: &'static str, message: &str) -> Self {
+        Check {
+            name,
+            status: Status::Skip,
+            message: message.to_string(),
+            examples: Vec::new(),
+        }
+    }
+
+    /// Construct a check from failing items, fails if there is at least one.
+    fn from_failures(name: &'static str, what: &str, failures: Vec<String>) -> Self {
+        if failures.is_empty() {
+            return Check {
+                name,
+                status: Status::Pass,
+                message: String::new(),
+                examples: Vec::new(),
+            };
+        }
+
+        Check {
+            name,
+            status: Status::Fail,
+            message: format!("{} {}", failures.len(), what),
+            examples: failures.into_iter().take(MAX_EXAMPLES).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.message.is_empty() {
+            writeln!(f, "{}\t{}", self.status, self.name)?;
+        } else {
+            writeln!(f, "{}\t{}: {}", self.status, self.name, self.message)?;
+        }
+
+        for example in &self.examples {
+            writeln!(f, "\t{}", example)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Describe a storage row by its word, or as a subword row.
+fn row_name(vocab: &VocabWrap, idx: usize) -> String {
+    if idx < vocab.words_len() {
+        format!("'{}'", vocab.words()[idx])
+    } else {
+        format!("subword row {}", idx)
+    }
+}
+
+fn check_shape(embeddings: &Embeddings<VocabWrap, StorageWrap>) -> Check {
+    let vocab = embeddings.vocab();
+    let (rows, _) = embeddings.storage().shape();
+
+    let mut failures = Vec::new();
+    if vocab.vocab_len() != rows {
+        failures.push(format!(
+            "vocabulary has {} entries ({} words, {} subwords), storage has {} rows",
+            vocab.vocab_len(),
+            vocab.words_len(),
+            vocab.vocab_len() - vocab.words_len(),
+            rows
+        ));
+    }
+
+    if let Some(norms) = embeddings.norms() {
+        if norms.len() != vocab.words_len() {
+            failures.push(format!(
+                "vocabulary has {} words, there are {} norms",
+                vocab.words_len(),
+                norms.len()
+            ));
+        }
+    }
+
+    Check::from_failures("vocabulary and storage size", "size mismatch(es)", failures)
+}
+
+fn check_words(words: &[String]) -> Check {
+    let mut seen = HashSet::new();
+    let mut failures = Vec::new();
+    for (idx, word) in words.iter().enumerate() {
+        if word.trim().is_empty() {
+            failures.push(format!("empty word at index {}", idx));
+        } else if !seen.insert(word.as_str()) {
+            failures.push(format!("duplicate word '{}' at index {}", word, idx));
+        }
+    }
+
+    Check::from_failures("vocabulary entries", "empty or duplicate word(s)", failures)
+}
+
+fn check_values(embeddings: &Embeddings<VocabWrap, StorageWrap>) -> Check {
+    let storage = embeddings.storage();
+    let (rows, _) = storage.shape();
+
+    let failures = (0..rows)
+        .filter(|&idx| storage.embedding(idx).iter().any(|v| !v.is_finite()))
+        .map(|idx| format!("{} contains NaN or Inf", row_name(embeddings.vocab(), idx)))
+        .collect();
+
+    Check::from_failures("finite values", "row(s) with NaN or Inf", failures)
+}
+
+fn check_zero_rows(embeddings: &Embeddings<VocabWrap, StorageWrap>) -> Check {
+    let storage = embeddings.storage();
+    let (rows, _) = storage.shape();
+
+    let failures = (0..rows)
+        .filter(|&idx| storage.embedding(idx).iter().all(|&v| v == 0.))
+        .map(|idx| format!("{} is all-zero", row_name(embeddings.vocab(), idx)))
+        .collect();
+
+    Check::from_failures("non-zero rows", "all-zero row(s)", failures)
+}
+
+/// Compare stored norms with the recomputed norms.
+///
+/// The unnormalized embeddings are not stored, so the norms cannot be
+/// recomputed from them. Instead, word embeddings are stored
+/// l2-normalized, so that the unnormalized embedding `embedding * norm`
+/// has the stored norm exactly when `embedding` has unit length. This
+/// check verifies that `| |embedding| - 1 | <= tolerance` and that the
+/// stored norm is finite and non-negative.
+fn check_norms(embeddings: &Embeddings<VocabWrap, StorageWrap>, tolerance: f32) -> Check {
+    let norms = match embeddings.norms() {
+        Some(norms) => norms,
+        None => return Check::skip("norms", "embeddings do not have norms"),
+    };
+
+    if let StorageWrap::QuantizedArray(_) | StorageWrap::MmapQuantizedArray(_) =
+        embeddings.storage()
+    {
+        return Check::skip("norms", "quantized embeddings are not exactly normalized");
+    }
+
+    let storage = embeddings.storage();
+    let n_words = embeddings.vocab().words_len().min(norms.len());
+    let failures = (0..n_words)
+        .filter_map(|idx| {
+            let embedding = storage.embedding(idx);
+            let stored = norms[idx];
+            let embedding_norm = embedding.dot(&embedding).sqrt();
+            if !stored.is_finite() || stored < 0. || (embedding_norm - 1.).abs() > tolerance {
+                Some(format!(
+                    "{}: stored norm {}, embedding norm {}",
+                    row_name(embeddings.vocab(), idx),
+                    stored,
+                    embedding_norm
+                ))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    Check::from_failures("norms", "inconsistent norm(s)", failures)
+}
+
+fn is_text_based(format: EmbeddingFormat) -> bool {
+    use self::EmbeddingFormat::*;
+    matches!(format, Text | TextDims | Word2Vec)
+}
+
+/// Read the words of embeddings in a text-based format.
+///
+/// Words are split and trimmed in the same way as the finalfusion
+/// readers, an empty line is an empty word. Reading stops at the first
+/// malformed record, the error is reported when the embeddings are read.
+fn read_words(mut reader: impl BufRead, format: EmbeddingFormat) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut buf = Vec::new();
+
+    if format == EmbeddingFormat::Text || format == EmbeddingFormat::TextDims {
+        if format == EmbeddingFormat::TextDims {
+            reader.read_until(b'\n', &mut buf)?;
+        }
+
+        for line in reader.split(b'\n') {
+            let line = line?;
+            let word = String::from_utf8_lossy(&line)
+                .split(|c: char| c.is_ascii_whitespace())
+                .find(|part| !part.is_empty())
+                .unwrap_or_default()
+                .to_owned();
+            words.push(word);
+        }
+
+        return Ok(words);
+    }
+
+    reader.read_until(b'\n', &mut buf)?;
+    let header = String::from_utf8_lossy(&buf);
+    let (n_words, dims) = match header.split_whitespace().collect::<Vec<_>>()[..] {
+        [n_words, dims] => match (n_words.parse::<usize>(), dims.parse::<u64>()) {
+            (Ok(n_words), Ok(dims)) => (n_words, dims),
+            _ => return Ok(words),
+        },
+        _ => return Ok(words),
+    };
+
+    for _ in 0..n_words {
+        buf.clear();
+        if reader.read_until(b' ', &mut buf)? == 0 {
+            break;
+        }
+        words.push(String::from_utf8_lossy(&buf).trim().to_owned());
+
+        let embedding_len = dims * std::mem::size_of::<f32>() as u64;
+        if io::copy(&mut (&mut reader).take(embedding_len), &mut io::sink())? != embedding_len {
+            break;
+        }
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use finalfusion::norms::NdNorms;
+    use finalfusion::prelude::*;
+    use finalfusion::storage::NdArray;
+    use finalfusion::vocab::SimpleVocab;
+    use ndarray::{array, Array1, Array2};
+    use tempfile::NamedTempFile;
+
+    use super::{
+        check_norms, check_values, check_words, check_zero_rows, read_words, Status, ValidateApp,
+    };
+    use crate::io::EmbeddingFormat;
+
+    fn embeddings(matrix: Array2<f32>, norms: Array1<f32>) -> Embeddings<VocabWrap, StorageWrap> {
+        let words = (0..matrix.nrows())
+            .map(|idx| format!("w{}", idx))
+            .collect::<Vec<_>>();
+        Embeddings::new(
+            None,
+            SimpleVocab::new(words),
+            NdArray::from(matrix),
+            NdNorms::new(norms),
+        )
+        .into()
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|&word| word.to_owned()).collect()
+    }
+
+    #[test]
+    fn duplicate_word_fails() {
+        let check = check_words(&words(&["foo", "bar", "foo"]));
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.examples, vec!["duplicate word 'foo' at index 2"]);
+    }
+
+    #[test]
+    fn empty_word_fails() {
+        let check = check_words(&words(&["foo", "", "bar"]));
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.examples, vec!["empty word at index 1"]);
+        assert_eq!(check_words(&words(&["foo", "bar"])).status, Status::Pass);
+    }
+
+    #[test]
+    fn reads_words_of_text_formats() {
+        let text = "foo 1 2\n\nfoo 3 4";
+        assert_eq!(
+            read_words(text.as_bytes(), EmbeddingFormat::Text).unwrap(),
+            words(&["foo", "", "foo"])
+        );
+        assert_eq!(
+            read_words(
+                format!("3 2\n{}\n", text).as_bytes(),
+                EmbeddingFormat::TextDims
+            )
+            .unwrap(),
+            words(&["foo", "", "foo"])
+        );
+    }
+
+    #[test]
+    fn reads_words_of_word2vec() {
+        let mut data = b"2 1\n".to_vec();
+        for word in &["foo", "\nfoo"] {
+            data.extend_from_slice(word.as_bytes());
+            data.push(b' ');
+            data.extend_from_slice(&1f32.to_le_bytes());
+        }
+        assert_eq!(
+            read_words(&data[..], EmbeddingFormat::Word2Vec).unwrap(),
+            words(&["foo", "foo"])
+        );
+    }
+
+    #[test]
+    fn duplicate_word_in_file_is_reported() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(b"foo 1 0\nbar 0 1\nfoo 1 1\n").unwrap();
+        let app = ValidateApp {
+            input_filename: file.path().to_str().unwrap().to_owned(),
+            input_format: EmbeddingFormat::Text,
+            norm_tolerance: 1e-3,
+        };
+
+        let checks = app.checks().unwrap();
+        assert_eq!(checks[0].status, Status::Fail);
+        assert!(checks[1..].iter().all(|check| check.status == Status::Skip));
+    }
+
+    #[test]
+    fn non_finite_value_fails() {
+        let check = check_values(&embeddings(
+            array![[1., 0.], [f32::NAN, 0.], [0., f32::INFINITY]],
+            array![1., 1., 1.],
+        ));
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(
+            check.examples,
+            vec!["'w1' contains NaN or Inf", "'w2' contains NaN or Inf"]
+        );
+    }
+
+    #[test]
+    fn zero_row_fails() {
+        let check = check_zero_rows(&embeddings(array![[1., 0.], [0., 0.]], array![1., 0.]));
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.examples, vec!["'w1' is all-zero"]);
+    }
+
+    #[test]
+    fn non_unit_norm_fails() {
+        let check = check_norms(
+            &embeddings(array![[1., 0.], [0.6, 0.8], [0.5, 0.5]], array![2., 3., 1.]),
+            1e-3,
+        );
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.examples.len(), 1);
+        assert!(check.examples[0].starts_with("'w2': "));
+    }
+
+    #[test]
+    fn negative_norm_fails() {
+        let check = check_norms(&embeddings(array![[1., 0.]], array![-1.]), 1e-3);
+        assert_eq!(check.status, Status::Fail);
+    }
+}